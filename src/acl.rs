@@ -0,0 +1,239 @@
+use crate::wire::Frame;
+use anyhow::anyhow;
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+    List,
+    Stat,
+    Checksum,
+    Delete,
+}
+
+impl Operation {
+    const ALL: [Operation; 6] = [
+        Operation::Read,
+        Operation::Write,
+        Operation::List,
+        Operation::Stat,
+        Operation::Checksum,
+        Operation::Delete,
+    ];
+
+    /// Operation and path of a command frame, None for any other frame.
+    pub fn of(frame: &Frame) -> Option<(Operation, &Path)> {
+        match frame {
+            Frame::Read(f) => Some((Operation::Read, f.path())),
+            Frame::Write(f) => Some((Operation::Write, f.path())),
+            Frame::List(f) => Some((Operation::List, f.path())),
+            Frame::Stat(f) => Some((Operation::Stat, f.path())),
+            Frame::Checksum(f) => Some((Operation::Checksum, f.path())),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Operation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Operation::Read),
+            "write" => Ok(Operation::Write),
+            "list" => Ok(Operation::List),
+            "stat" => Ok(Operation::Stat),
+            "checksum" => Ok(Operation::Checksum),
+            "delete" => Ok(Operation::Delete),
+            _ => Err(anyhow!("Unknown operation: {}", s)),
+        }
+    }
+}
+
+/// Grants a client, or every client, a set of operations below a path prefix.
+///
+/// Clients are told apart by the source IP of their packets, which isn't
+/// authenticated and can be spoofed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AclRule {
+    pub client: Option<IpAddr>,
+    pub prefix: PathBuf,
+    pub operations: Vec<Operation>,
+}
+
+impl AclRule {
+    pub fn new(client: Option<IpAddr>, prefix: &Path, operations: Vec<Operation>) -> Self {
+        AclRule {
+            client,
+            prefix: normalize(prefix).unwrap_or_default(),
+            operations,
+        }
+    }
+
+    fn matches(&self, client: Option<IpAddr>, operation: Operation, path: &Path) -> bool {
+        self.client.is_none_or(|c| Some(c) == client)
+            && self.operations.contains(&operation)
+            && path.starts_with(&self.prefix)
+    }
+}
+
+impl FromStr for AclRule {
+    type Err = anyhow::Error;
+
+    /// Parse a rule from `client:prefix:ops`, where client is an IP address,
+    /// in brackets for IPv6 like `[::1]`, or `*`, an empty prefix or `*`
+    /// matches every path and ops is a comma separated list of operations or
+    /// `all`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (client, rest) = match s.strip_prefix('[') {
+            Some(v6) => v6
+                .split_once("]:")
+                .ok_or_else(|| anyhow!("Expected [client]:prefix:ops, got {}", s))?,
+            None => s
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Expected client:prefix:ops, got {}", s))?,
+        };
+        let (prefix, ops) = rest
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("Expected client:prefix:ops, got {}", s))?;
+
+        let client = match client {
+            "*" => None,
+            c => Some(
+                c.parse()
+                    .map_err(|e| anyhow!("Invalid client {}: {}", c, e))?,
+            ),
+        };
+        let prefix = match prefix {
+            "*" => "",
+            p => p,
+        };
+        if normalize(Path::new(prefix)).is_none() {
+            return Err(anyhow!("Invalid prefix: {}", prefix));
+        }
+        let operations = match ops {
+            "all" => Operation::ALL.to_vec(),
+            ops => ops
+                .split(',')
+                .map(Operation::from_str)
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+        Ok(AclRule::new(client, Path::new(prefix), operations))
+    }
+}
+
+/// Access control list checked for every command a client issues.
+///
+/// An empty list permits everything, otherwise a command is only permitted
+/// if at least one rule grants it.
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+}
+
+impl Acl {
+    pub fn new(rules: Vec<AclRule>) -> Self {
+        Acl { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn permits(&self, client: Option<IpAddr>, operation: Operation, path: &Path) -> bool {
+        if self.rules.is_empty() {
+            return true;
+        }
+        match normalize(path) {
+            Some(path) => self
+                .rules
+                .iter()
+                .any(|rule| rule.matches(client, operation, &path)),
+            None => false,
+        }
+    }
+}
+
+//drop `.` components, paths containing `..` can't be matched against a prefix
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => return None,
+            c => normalized.push(c),
+        }
+    }
+    Some(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const ALICE: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const BOB: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    #[test]
+    fn test_parse_rule() {
+        assert_eq!(
+            "10.0.0.1:logs:read,stat".parse::<AclRule>().unwrap(),
+            AclRule::new(
+                Some(ALICE),
+                Path::new("logs"),
+                vec![Operation::Read, Operation::Stat]
+            )
+        );
+        assert_eq!(
+            "*:*:all".parse::<AclRule>().unwrap(),
+            AclRule::new(None, Path::new(""), Operation::ALL.to_vec())
+        );
+        assert!("10.0.0.1:logs".parse::<AclRule>().is_err());
+        assert!("10.0.0.1:logs:fly".parse::<AclRule>().is_err());
+        assert!("nobody:logs:read".parse::<AclRule>().is_err());
+        assert!("*:../etc:read".parse::<AclRule>().is_err());
+    }
+
+    #[test]
+    fn test_parse_ipv6_rule() {
+        assert_eq!(
+            "[fe80::1]:logs:read".parse::<AclRule>().unwrap(),
+            AclRule::new(
+                Some(IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1))),
+                Path::new("logs"),
+                vec![Operation::Read]
+            )
+        );
+        assert_eq!(
+            "[::1]:*:all".parse::<AclRule>().unwrap().client,
+            Some(IpAddr::V6(Ipv6Addr::LOCALHOST))
+        );
+        assert!("[::1:logs:read".parse::<AclRule>().is_err());
+        assert!("::1:logs:read".parse::<AclRule>().is_err());
+    }
+
+    #[test]
+    fn test_empty_acl_permits_everything() {
+        let acl = Acl::default();
+        assert!(acl.permits(Some(ALICE), Operation::Write, Path::new("/etc/passwd")));
+    }
+
+    #[test]
+    fn test_permits() {
+        let acl = Acl::new(vec![
+            "10.0.0.1:logs:read,stat".parse().unwrap(),
+            "*:drop:write".parse().unwrap(),
+        ]);
+        assert!(acl.permits(Some(ALICE), Operation::Read, Path::new("logs/app.log")));
+        assert!(acl.permits(Some(ALICE), Operation::Read, Path::new("./logs/app.log")));
+        assert!(!acl.permits(Some(ALICE), Operation::Write, Path::new("logs/app.log")));
+        assert!(!acl.permits(Some(BOB), Operation::Read, Path::new("logs/app.log")));
+        assert!(!acl.permits(Some(ALICE), Operation::Read, Path::new("logsfoo/app.log")));
+        assert!(!acl.permits(Some(ALICE), Operation::Read, Path::new("drop/../logs/a")));
+        assert!(acl.permits(Some(BOB), Operation::Write, Path::new("drop/upload.bin")));
+    }
+}
@@ -1,300 +1,833 @@
+use crate::connection::Connection;
+use crate::glob::{self, Filter};
+use crate::journal::Journal;
 use crate::loss_simulation::LossSimulation;
-use crate::stream_handler::stream_handler;
+use crate::migration::{self, Origin, PeerAddress};
+use crate::storage::LocalStorage;
+use crate::stream_handler::{
+    list_dir, prefix_crc32, sha256_digest, stream_handler, HandlerContext,
+};
+use crate::sync::{self, SyncDirection, SyncTransfer};
 use crate::wire::*;
 use anyhow::{anyhow, Context};
-use futures::channel::mpsc::{channel, Receiver, Sender};
-use futures::{SinkExt, StreamExt};
+use futures::channel::mpsc::{channel, Receiver, SendError, Sender};
+use futures::{Sink, SinkExt, StreamExt};
 use log::{debug, error, info, warn};
-use std::fs::remove_file;
-use std::net::{Ipv4Addr, SocketAddrV4};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::net::UdpSocket;
-use tokio::task::spawn_blocking;
-use tokio::time::{sleep, timeout};
+use std::cmp::min;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufReader};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+use tokio::task::{spawn_blocking, JoinHandle};
+use tokio::time::{timeout, Instant};
+
+//how often the progress of downloads is written to their journals
+const JOURNAL_INTERVAL: Duration = Duration::from_secs(1);
+
+//transfers running at the same time, more are done in batches of this size
+const MAX_TRANSFERS: usize = 256;
+
+/// A file transferred between a local and a remote path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transfer {
+    pub local: PathBuf,
+    pub remote: PathBuf,
+}
+
+impl Transfer {
+    pub fn new(local: PathBuf, remote: PathBuf) -> Self {
+        Transfer { local, remote }
+    }
+
+    /// Parse a download from `remote[:local]`, see [`split_spec`]. Escapes of
+    /// glob syntax are removed from the remote path.
+    ///
+    /// Without a local path the file is stored under its remote path, or
+    /// under its file name if it goes to `output_dir`. Local paths are
+    /// relative to `output_dir` if one is given.
+    pub fn download(spec: &str, output_dir: Option<&Path>) -> anyhow::Result<Self> {
+        let (remote, local) = split_spec(spec)?;
+        let remote = PathBuf::from(glob::unescape(&remote.to_string_lossy()));
+        let local = match (output_dir, local) {
+            (None, Some(local)) => local,
+            (None, None) => remote.clone(),
+            (Some(dir), Some(local)) => dir.join(local),
+            (Some(dir), None) => match remote.file_name() {
+                Some(name) => dir.join(name),
+                None => return Err(anyhow!("No file name in {:?}", spec)),
+            },
+        };
+        Ok(Transfer::new(local, remote))
+    }
+}
+
+/// Split a download spec into the remote and the optional local path.
+///
+/// The paths are separated by the first colon, colons in the remote path
+/// are escaped as `\:`.
+pub fn split_spec(spec: &str) -> anyhow::Result<(PathBuf, Option<PathBuf>)> {
+    let mut remote = String::new();
+    let mut local: Option<String> = None;
+    let mut chars = spec.chars().peekable();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' if chars.peek() == Some(&':') => chars.next().unwrap_or(c),
+            ':' if local.is_none() => {
+                local = Some(String::new());
+                continue;
+            }
+            c => c,
+        };
+        local.as_mut().unwrap_or(&mut remote).push(c);
+    }
+    if remote.is_empty() || local.as_ref().is_some_and(String::is_empty) {
+        return Err(anyhow!("Expected remote[:local], got {:?}", spec));
+    }
+    Ok((remote.into(), local.map(PathBuf::from)))
+}
+
+// a path to list on the server that isn't taken for a glob
+fn escaped(path: &Path) -> PathBuf {
+    glob::escape(&path.to_string_lossy()).into()
+}
+
+// whether a path received from the server stays below the directory it is joined to
+fn is_relative(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Remote files matching a glob, stored below a local directory by their
+/// path from the leading directories of the glob.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GlobTransfer {
+    pub local: PathBuf,
+    pub remote: PathBuf,
+}
+
+impl GlobTransfer {
+    pub fn new(local: PathBuf, remote: PathBuf) -> Self {
+        GlobTransfer { local, remote }
+    }
+
+    /// Parse a glob download from `remote[:local]`.
+    ///
+    /// Without a local directory the files are stored under their remote
+    /// path, or below `output_dir`. Local directories are relative to
+    /// `output_dir` if one is given.
+    pub fn download(spec: &str, output_dir: Option<&Path>) -> anyhow::Result<Self> {
+        let (remote, local) = split_spec(spec)?;
+        let local = match (output_dir, local) {
+            (None, Some(local)) => local,
+            (None, None) => glob::split_base(&remote).0,
+            (Some(dir), Some(local)) => dir.join(local),
+            (Some(dir), None) => dir.to_path_buf(),
+        };
+        Ok(GlobTransfer::new(local, remote))
+    }
+
+    /// The downloads of the files among the `entries` the server matched.
+    fn expand(&self, entries: &[ListEntry]) -> Vec<Transfer> {
+        let (base, _) = glob::split_base(&self.remote);
+        entries
+            .iter()
+            .filter(|entry| !entry.is_dir)
+            .filter_map(|entry| match entry.path.strip_prefix(&base) {
+                Ok(path) if is_relative(path) => {
+                    Some(Transfer::new(self.local.join(path), entry.path.clone()))
+                }
+                _ => {
+                    warn!("Skipping invalid path in listing: {:?}", entry.path);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// A directory transferred with everything below it that the filter selects.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeTransfer {
+    pub local: PathBuf,
+    pub remote: PathBuf,
+    pub filter: Filter,
+}
+
+impl TreeTransfer {
+    pub fn new(local: PathBuf, remote: PathBuf, filter: Filter) -> Self {
+        TreeTransfer {
+            local,
+            remote,
+            filter,
+        }
+    }
+
+    /// The selected directories and file transfers among the `entries`
+    /// listed on the sending side, directories relative to the tree.
+    fn expand(&self, entries: &[ListEntry]) -> (Vec<PathBuf>, Vec<Transfer>) {
+        let mut dirs = Vec::new();
+        let mut files = Vec::new();
+        for entry in entries {
+            // A listing must not lead out of the tree
+            if !is_relative(&entry.path) {
+                warn!("Skipping invalid path in listing: {:?}", entry.path);
+                continue;
+            }
+            if !self.filter.selects(&entry.path, entry.is_dir) {
+                continue;
+            }
+            match entry.is_dir {
+                true => dirs.push(entry.path.clone()),
+                false => files.push(Transfer::new(
+                    self.local.join(&entry.path),
+                    self.remote.join(&entry.path),
+                )),
+            }
+        }
+        (dirs, files)
+    }
+}
 
 #[derive(Debug)]
 pub struct ClientConfig {
     pub host: Ipv4Addr,
     pub port: u16,
-    pub files: Vec<PathBuf>,
+    pub downloads: Vec<Transfer>,
     pub loss_sim: Option<LossSimulation>,
+    /// Apply the mode bits and mtime of the remote files to the downloads.
+    pub preserve: bool,
+    pub uploads: Vec<Transfer>,
+    /// Continue downloads at the offsets recorded in their journals.
+    pub continue_downloads: bool,
+    /// Compare each completed download with the server's SHA-256 of the file,
+    /// a mismatch is downloaded once more before the file is reported as corrupt.
+    pub verify: bool,
+    pub download_dirs: Vec<TreeTransfer>,
+    pub download_globs: Vec<GlobTransfer>,
+    pub upload_dirs: Vec<TreeTransfer>,
+    pub syncs: Vec<SyncTransfer>,
+    /// Only print what the syncs would transfer and delete.
+    pub dry_run: bool,
 }
 
 impl ClientConfig {
     pub fn new(
         host: Ipv4Addr,
         port: u16,
-        files: Vec<PathBuf>,
+        downloads: Vec<Transfer>,
         loss_sim: Option<LossSimulation>,
     ) -> Self {
         Self {
             host,
             port,
-            files,
+            downloads,
             loss_sim,
+            preserve: false,
+            uploads: Vec::new(),
+            continue_downloads: false,
+            verify: false,
+            download_dirs: Vec::new(),
+            download_globs: Vec::new(),
+            upload_dirs: Vec::new(),
+            syncs: Vec::new(),
+            dry_run: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Direction {
+    Download,
+    Upload,
+}
+
+// what the frames on a stream of a batch of transfers are for
+#[derive(Clone, Copy, Debug)]
+enum Role {
+    Transfer(usize),
+    // checksum of the remote file for the journal and to verify the download
+    Checksum(usize),
+}
+
+/// Stream ids of a connection.
+///
+/// Ids are handed out in order, those of finished streams only once all
+/// others were used, so a late frame of a finished stream can't be taken
+/// for one of a new stream right away.
+#[derive(Debug)]
+struct StreamIds {
+    next: u32,
+    free: VecDeque<u16>,
+}
+
+impl Default for StreamIds {
+    fn default() -> Self {
+        StreamIds {
+            next: 1,
+            free: VecDeque::new(),
+        }
+    }
+}
+
+impl StreamIds {
+    fn allocate(&mut self) -> anyhow::Result<u16> {
+        if let Ok(id) = u16::try_from(self.next) {
+            self.next += 1;
+            return Ok(id);
         }
+        self.free
+            .pop_front()
+            .ok_or_else(|| anyhow!("All stream ids are in use"))
+    }
+
+    fn release(&mut self, id: u16) {
+        self.free.push_back(id);
     }
 }
 
 #[derive(Debug)]
 pub struct Client {
     config: ClientConfig,
-    sinks: Vec<Sender<Frame>>,
-    failed: Vec<bool>,
 }
 
 impl Client {
     pub fn new(config: ClientConfig) -> Self {
-        Client {
-            config,
-            sinks: Vec::new(),
-            failed: Vec::new(),
+        Client { config }
+    }
+
+    // journal to continue the download to `local` from, with --continue
+    fn continued_journal(&self, local: &Path, remote: &Path) -> anyhow::Result<Option<Journal>> {
+        if !self.config.continue_downloads {
+            return Ok(None);
+        }
+        match Journal::load(local).context(format!("Failed to read journal of {:?}", local))? {
+            Some(journal) if journal.matches(remote, self.config.host, self.config.port) => {
+                Ok(Some(journal))
+            }
+            Some(_) => {
+                warn!(
+                    "Journal of {:?} is for another transfer, ignoring it",
+                    local
+                );
+                Ok(None)
+            }
+            None => Ok(None),
         }
     }
 
-    pub async fn start(&mut self) -> Result<(), anyhow::Error> {
-        // Connect the client to the specified server
-        let socket = match UdpSocket::bind("0.0.0.0:0").await {
-            Ok(socket) => {
-                match socket
-                    .connect(SocketAddrV4::new(self.config.host, self.config.port))
-                    .await
-                {
-                    Ok(_) => socket,
-                    Err(e) => return Err(anyhow!("Failed to connect to server: {}", e)),
-                }
+    // what the sync has to transfer and delete, found by comparing both trees
+    async fn plan_sync(
+        &self,
+        sync: &SyncTransfer,
+        requests: &mut Requests<'_>,
+    ) -> anyhow::Result<SyncPlan> {
+        if sync.delete && sync.direction == SyncDirection::Upload {
+            return Err(anyhow!(
+                "Files can only be deleted when syncing from the server"
+            ));
+        }
+
+        let mut remote = match requests.stat(&sync.remote).await? {
+            Some(entry) if entry.is_dir => requests
+                .list(&[&escaped(&sync.remote)])
+                .await?
+                .pop()
+                .unwrap_or_default(),
+            Some(_) => {
+                return Err(anyhow!(
+                    "{:?} is not a directory on the server",
+                    sync.remote
+                ))
+            }
+            None if sync.direction == SyncDirection::Upload => Vec::new(),
+            None => {
+                return Err(anyhow!(
+                    "No such directory on the server: {:?}",
+                    sync.remote
+                ))
             }
-            Err(e) => return Err(anyhow!("Failed to bind socket: {}", e)),
         };
-        let conn = Arc::new(socket);
-        let mut loss_sim = self
-            .config
-            .loss_sim
-            .clone()
-            .map(|loss_sim| Arc::new(Mutex::new(loss_sim)));
-        info! {"Connected to server at {}:{}", self.config.host, self.config.port};
-
-        // TODO: check buffer sizes
-        // TODO: handle congestion control
-        // idea: https://excalidraw.com/#json=tbYyeXwmjsAWzIbHJqoa2,lxc2VI0v4LzKGLqVhFwotw
-        // send frames on one stream per file
-        // one stream handler per file
-
-        let mut packet_id = 1; // client counter for the packet_id
-        let mut last_recv_packet_id;
-        let mut recv_buf: [u8; 2048] = [0; 2048];
-
-        // Start connection establishment and ConnID
-        // TODO: handle connection establishment with CID change Frame
-        let packet = Packet::new(0, packet_id);
-        let bytes = packet.assemble();
-        conn.send(&bytes).await.context("Failed to send packet")?;
-        packet_id += 1;
-
-        let size = conn.recv(&mut recv_buf).await?;
-        let packet = Packet::parse_buf(&recv_buf[..size]).context("Failed to parse packet")?;
-
-        // Check for connection establishment
-        let conn_id = packet.header().connection_id;
-        if conn_id == 0 {
-            return Err(anyhow!("Failed to establish connection, received ConnID 0"));
+        remote.retain(|entry| {
+            is_relative(&entry.path) || {
+                warn!("Skipping invalid path in listing: {:?}", entry.path);
+                false
+            }
+        });
+        let mut local = match fs::metadata(&sync.local) {
+            Ok(metadata) if metadata.is_dir() => list_dir(&LocalStorage, &sync.local)
+                .context(format!("Failed to list {:?}", sync.local))?,
+            Ok(_) => return Err(anyhow!("{:?} is not a directory", sync.local)),
+            Err(e)
+                if e.kind() == io::ErrorKind::NotFound
+                    && sync.direction == SyncDirection::Download =>
+            {
+                Vec::new()
+            }
+            Err(e) => return Err(e).context(format!("Failed to list {:?}", sync.local)),
+        };
+        // Journals of interrupted downloads are not part of the tree
+        local.retain(|entry| !Journal::is_journal(&entry.path));
+
+        let mut comparison = match sync.direction {
+            SyncDirection::Download => sync::compare(&remote, &local),
+            SyncDirection::Upload => sync::compare(&local, &remote),
         };
-        last_recv_packet_id = packet.header().packet_id;
-        if last_recv_packet_id != 1 {
+        for path in &comparison.conflicts {
             warn!(
-                "Received unexpected packet_id from the server during connection establishment: {}",
-                last_recv_packet_id
+                "{:?} is a file on one side and a directory on the other, skipping it",
+                path
             );
         }
 
-        let mut transmission_complete = vec![false; self.config.files.len()];
+        // Files of the same size are only transferred if their checksums differ
+        let remotes: Vec<PathBuf> = comparison
+            .uncertain
+            .iter()
+            .map(|path| sync.remote.join(path))
+            .collect();
+        let remotes: Vec<&Path> = remotes.iter().map(PathBuf::as_path).collect();
+        let checksums = requests.checksums(&remotes).await?;
+        let locals: Vec<PathBuf> = comparison
+            .uncertain
+            .iter()
+            .map(|path| sync.local.join(path))
+            .collect();
+        let digests = spawn_blocking(move || {
+            locals
+                .iter()
+                .map(|local| fs::File::open(local).and_then(|f| sha256_digest(BufReader::new(f))))
+                .collect::<Vec<_>>()
+        })
+        .await?;
+        let mtimes: HashMap<&Path, i64> = remote
+            .iter()
+            .map(|entry| (entry.path.as_path(), entry.mtime))
+            .collect();
+        for ((path, checksum), digest) in comparison.uncertain.drain(..).zip(checksums).zip(digests)
+        {
+            match (checksum, digest) {
+                (Some(checksum), Ok(digest)) if checksum == digest.as_ref() => {
+                    comparison.unchanged += 1;
+                    // Unchanged downloads take the remote modification time so they
+                    // aren't hashed again next time, the server can't set it for uploads
+                    if sync.direction == SyncDirection::Download && !self.config.dry_run {
+                        let local = sync.local.join(&path);
+                        if let Err(e) = set_mtime(&local, mtimes[path.as_path()]) {
+                            warn!("Failed to set modification time of {:?}: {}", local, e);
+                        }
+                    }
+                }
+                _ => comparison.changed.push(path),
+            }
+        }
+        info!(
+            "{} files of {:?} are unchanged, {} to transfer",
+            comparison.unchanged,
+            sync.local,
+            comparison.changed.len()
+        );
 
-        let (mut assembler_sink, mut assembler_rx): (Sender<Frame>, Receiver<Frame>) = channel(3);
+        let mut plan = SyncPlan::default();
+        let transfers = comparison
+            .changed
+            .iter()
+            .map(|path| Transfer::new(sync.local.join(path), sync.remote.join(path)));
+        match sync.direction {
+            SyncDirection::Download => {
+                plan.dirs = (!sync.local.is_dir())
+                    .then(|| sync.local.clone())
+                    .into_iter()
+                    .chain(comparison.missing_dirs.iter().map(|p| sync.local.join(p)))
+                    .collect();
+                plan.downloads = transfers.collect();
+            }
+            SyncDirection::Upload => plan.uploads = transfers.collect(),
+        }
+        if sync.delete {
+            plan.deletions = comparison
+                .extraneous
+                .iter()
+                .map(|path| sync.local.join(path))
+                .collect();
+        }
+        Ok(plan)
+    }
 
-        debug! {"Starting {} stream handlers", self.config.files.len()};
+    pub async fn start(&mut self) -> Result<(), anyhow::Error> {
+        // Connect the client to the specified server
+        let (socket, server) =
+            server_socket(SocketAddrV4::new(self.config.host, self.config.port)).await?;
+        let (input, output) = transport(socket, server, self.config.loss_sim.clone());
+        let mut connection = Connection::connect(input, output).await?;
+        info!(
+            "Connected to server at {}:{}, connection {}",
+            self.config.host,
+            self.config.port,
+            connection.id()
+        );
+
+        // Directories and globs are listed first, their files are then
+        // transferred like any other
+        let mut downloads = self.config.downloads.clone();
+        let remotes: Vec<PathBuf> = self
+            .config
+            .download_dirs
+            .iter()
+            .map(|tree| escaped(&tree.remote))
+            .chain(self.config.download_globs.iter().map(|g| g.remote.clone()))
+            .collect();
+        let remotes: Vec<&Path> = remotes.iter().map(PathBuf::as_path).collect();
+        let mut ids = StreamIds::default();
+        let mut requests = Requests {
+            connection: &mut connection,
+            ids: &mut ids,
+        };
+        let mut listings = requests.list(&remotes).await?.into_iter();
+        for (tree, entries) in self.config.download_dirs.iter().zip(listings.by_ref()) {
+            let (dirs, files) = tree.expand(&entries);
+            for dir in std::iter::once(PathBuf::new()).chain(dirs) {
+                let dir = tree.local.join(dir);
+                fs::create_dir_all(&dir).context(format!("Failed to create {:?}", dir))?;
+            }
+            downloads.extend(files);
+        }
+        for (glob, entries) in self.config.download_globs.iter().zip(listings) {
+            let files = glob.expand(&entries);
+            if files.is_empty() {
+                warn!("No files on the server match {:?}", glob.remote);
+            }
+            downloads.extend(files);
+        }
+        let mut uploads = self.config.uploads.clone();
+        for tree in &self.config.upload_dirs {
+            let entries = list_dir(&LocalStorage, &tree.local)
+                .context(format!("Failed to list {:?}", tree.local))?;
+            uploads.extend(tree.expand(&entries).1);
+        }
+
+        // Syncs only transfer what differs, extraneous files are deleted once
+        // everything else succeeded
+        let mut deletions = Vec::new();
+        for sync in &self.config.syncs {
+            let plan = self.plan_sync(sync, &mut requests).await?;
+            if self.config.dry_run {
+                print!("{}", plan);
+                continue;
+            }
+            for dir in &plan.dirs {
+                fs::create_dir_all(dir).context(format!("Failed to create {:?}", dir))?;
+            }
+            downloads.extend(plan.downloads);
+            uploads.extend(plan.uploads);
+            deletions.extend(plan.deletions);
+        }
+
+        // Two downloads to the same file would overwrite each other
+        let mut locals = HashSet::new();
+        if let Some(t) = downloads.iter().find(|t| !locals.insert(&t.local)) {
+            return Err(anyhow!("Multiple downloads to {:?}", t.local));
+        }
+
+        // Downloads come first, then uploads, at most MAX_TRANSFERS at a time
+        let streams: Vec<(Direction, PathBuf, PathBuf)> = downloads
+            .into_iter()
+            .map(|t| (Direction::Download, t.local, t.remote))
+            .chain(
+                uploads
+                    .into_iter()
+                    .map(|t| (Direction::Upload, t.local, t.remote)),
+            )
+            .collect();
+        let mut failed = 0;
+        for batch in streams.chunks(MAX_TRANSFERS) {
+            match self.transfer(&mut connection, &mut ids, batch).await? {
+                Some(n) => failed += n,
+                None => {
+                    connection.close().await?;
+                    return Err(anyhow!("The server closed the connection"));
+                }
+            }
+        }
+
+        debug!("Transmission complete. Closing connection...");
+        let mut outgoing = connection.sender();
+        outgoing.send(Frame::Exit(ExitFrame::new())).await?;
+        debug!("Sent ExitFrame to server");
+
+        // The connection is done once the server confirmed the rest
+        drop(outgoing);
+        connection.close().await?;
+
+        if failed == 0 {
+            for path in &deletions {
+                info!("Deleting {:?}", path);
+                let removed = match fs::symlink_metadata(path) {
+                    Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+                    Ok(_) => fs::remove_file(path),
+                    Err(e) => Err(e),
+                };
+                removed.context(format!("Failed to delete {:?}", path))?;
+            }
+        }
+        if failed > 0 {
+            return Err(anyhow!("{} of {} transfers failed", failed, streams.len()));
+        }
+        Ok(())
+    }
+
+    // run the transfers in `streams` to completion, returns how many failed
+    // or None if the server closed the connection
+    async fn transfer(
+        &self,
+        connection: &mut Connection,
+        ids: &mut StreamIds,
+        streams: &[(Direction, PathBuf, PathBuf)],
+    ) -> anyhow::Result<Option<usize>> {
+        let outgoing = connection.sender();
+        let mut transmission_complete = vec![false; streams.len()];
+
+        debug! {"Starting {} stream handlers", streams.len()};
+        let ctx = HandlerContext::default();
 
         // Setup up channels for stream handlers and assembler
-        for _ in &self.config.files {
+        let mut stream_ids = Vec::new();
+        let mut roles = HashMap::new();
+        let mut sinks = Vec::new();
+        let mut failed = vec![false; streams.len()];
+        let mut handlers = Vec::new();
+        for i in 0..streams.len() {
+            let stream_id = ids.allocate()?;
+            stream_ids.push(stream_id);
+            roles.insert(stream_id, Role::Transfer(i));
             let (tx, rx): (Sender<Frame>, Receiver<Frame>) = channel(3);
-            self.sinks.push(tx);
-            self.failed.push(false);
-            let assembly_sink = assembler_sink.clone();
+            sinks.push(tx);
 
             // Start the stream handlers
-            tokio::spawn(stream_handler(rx, assembly_sink));
+            handlers.push(Some(tokio::spawn(stream_handler(
+                rx,
+                handler_sink(&outgoing),
+                ctx.clone(),
+            ))));
         }
 
-        // Start the packet assembler and sender
-        let conn_clone = conn.clone();
-        let mut loss_sim_clone = loss_sim.clone();
-        tokio::spawn(async move {
-            while let Some(frame) = assembler_rx.next().await {
-                let mut packet = Packet::new(conn_id, packet_id);
-
-                sleep(Duration::from_micros(100)).await;
-                match frame {
-                    Frame::Ack(mut ack_frame) => {
-                        for _ in 0..10 {
-                            // info!("trying to reduce ack spam, take {}...", i);
-                            if let Ok(Some(frame2)) = assembler_rx.try_next() {
-                                match frame2 {
-                                    Frame::Ack(ack_frame2) => {
-                                        ack_frame = ack_frame2;
-                                    }
-                                    Frame::Error(error_frame) => {
-                                        warn!("Received error from writer: {} for stream {}, ignoring", error_frame.message(), error_frame.stream_id());
-                                        continue;
-                                    }
-                                    _ => {
-                                        packet.add_frame(ack_frame.into());
-                                        packet.add_frame(frame2);
-                                        break;
-                                    }
-                                }
-                            } else {
-                                packet.add_frame(ack_frame.into());
-                                break;
-                            }
-                        }
-                    }
-                    Frame::Error(error_frame) => {
-                        warn!(
-                            "Received error from writer: {} for stream {}, ignoring",
-                            error_frame.message(),
-                            error_frame.stream_id()
-                        );
-                        continue;
+        let flags = match self.config.preserve {
+            true => ReadFrame::FLAG_METADATA,
+            false => 0,
+        };
+
+        // Partial files from an earlier attempt are continued at their current
+        // size, or with --continue at the offset in their journal once the
+        // server confirms that the remote file didn't change
+        let mut offsets = vec![0; streams.len()];
+        let mut received = vec![0; streams.len()];
+        let mut pending = vec![None; streams.len()];
+        let mut journals: Vec<Option<Journal>> = vec![None; streams.len()];
+        let mut checksums: Vec<Option<Result<Vec<u8>, String>>> = vec![None; streams.len()];
+        let mut requested = vec![false; streams.len()];
+        let mut redownloaded = vec![false; streams.len()];
+
+        // Commands for the server, uploads are read locally once the server
+        // knows where to write them
+        let mut commands = Vec::new();
+        for (i, (direction, local, remote)) in streams.iter().enumerate() {
+            let stream_id = stream_ids[i];
+            match direction {
+                Direction::Download => {
+                    // The checksum for the journal and to verify the download
+                    // with is requested on a second stream, right away if a
+                    // journal is continued or the download verified
+                    let journal = self.continued_journal(local, remote)?;
+                    if journal.is_some() || self.config.verify {
+                        let checksum_id = ids.allocate()?;
+                        roles.insert(checksum_id, Role::Checksum(i));
+                        requested[i] = true;
+                        commands.push((
+                            Frame::Checksum(ChecksumFrame::new(checksum_id, remote)),
+                            None,
+                        ));
                     }
-                    _ => {
-                        packet.add_frame(frame);
+                    match journal {
+                        Some(journal) => pending[i] = Some(journal),
+                        None => {
+                            offsets[i] = partial_size(local)?;
+                            received[i] = offsets[i];
+                            start_download(
+                                &mut sinks[i],
+                                &outgoing,
+                                stream_id,
+                                flags,
+                                offsets[i],
+                                local,
+                                remote,
+                            )
+                            .await?;
+                        }
                     }
                 }
+                Direction::Upload => {
+                    let length = fs::metadata(local)
+                        .context(format!("Failed to read file {:?}", local))?
+                        .len();
+                    commands.push((
+                        Frame::Write(WriteFrame::new(
+                            stream_id,
+                            WriteFrame::FLAG_REPLACE,
+                            0,
+                            length,
+                            remote,
+                        )),
+                        Some((
+                            sinks[i].clone(),
+                            Frame::Read(ReadFrame::new(stream_id, flags, 0, 0, 0, local)),
+                        )),
+                    ));
+                }
+            }
+        }
 
-                if let Some(loss_sim) = loss_sim_clone.as_mut() {
-                    if loss_sim.lock().unwrap().drop_packet() {
-                        warn!(
-                            "Simulated loss of sent packet {} occurred!",
-                            packet.packet_id()
-                        );
-                        continue;
-                    }
+        debug! {"Sending {} commands to server", commands.len()};
+        // Send them from a separate task, the window may fill up before we
+        // start receiving the server's ACKs
+        let mut command_sink = outgoing.clone();
+        tokio::spawn(async move {
+            for (remote, local) in commands {
+                command_sink.send(remote).await?;
+                if let Some((mut sink, frame)) = local {
+                    sink.send(frame).await?;
                 }
-                debug!("Sending packet with packet {:?}", &packet);
-                let buf = spawn_blocking(move || packet.assemble()).await.unwrap();
-                conn_clone
-                    .send(&buf)
-                    .await
-                    .context("Failed to send packet")
-                    .unwrap();
-                packet_id += 1;
             }
+            Ok::<(), futures::channel::mpsc::SendError>(())
         });
 
-        debug! {"Sending {} WriteFrames to create files", self.config.files.len()};
-        // Send WriteFrame's to ourselves to create the requested files
-        for (i, path) in self.config.files.iter().enumerate() {
-            remove_file(path).context(format!("Failed to delete file {:?}", path))?;
-            let write_frame = WriteFrame::new((i + 1) as u16, 0, 0, path);
-            self.sinks[i].send(Frame::Write(write_frame)).await?;
-            debug!("Sent WriteFrame for file: {:?} to sink {}", path, i);
-        }
-
-        debug! {"Sending {} ReadFrames to server to read files", self.config.files.len()};
-        // Send the ReadFrame's to the server to read the entire files
-        for (i, path) in self.config.files.iter().enumerate() {
-            assembler_sink
-                .send(Frame::Read(ReadFrame::new(
-                    (i + 1) as u16,
-                    0,
-                    0,
-                    0,
-                    0,
-                    path,
-                )))
-                .await?;
-        }
-
-        // Receive the Packets from the server and switch the contained Frames to the corresponding sinks
-        while !transmission_complete.iter().all(|&x| x) {
-            // TODO send ack on timeout of a few ms maybe
-            let size = match timeout(Duration::from_millis(1000), conn.recv(&mut recv_buf)).await {
-                Ok(Ok(size)) => size,
-                Ok(Err(e)) => {
-                    error!("Failed to receive data from server: {}", e);
-                    assembler_sink
-                        .send(AckFrame::new(last_recv_packet_id).into())
-                        .await?;
-                    assembler_sink
-                        .send(AckFrame::new(last_recv_packet_id).into())
-                        .await?;
-                    continue;
-                }
-                Err(_) => {
-                    error!("Timeout while waiting for data from server");
-                    assembler_sink
-                        .send(AckFrame::new(last_recv_packet_id).into())
-                        .await?;
-                    assembler_sink
-                        .send(AckFrame::new(last_recv_packet_id).into())
-                        .await?;
-                    continue;
-                }
-            };
-            let packet = Packet::parse_buf(&recv_buf[..size])?;
-            if let Some(loss_sim) = loss_sim.as_mut() {
-                if loss_sim.lock().unwrap().drop_packet() {
-                    warn!(
-                        "Simulated loss of received packet {} occurred!",
-                        packet.packet_id()
-                    );
-                    continue;
+        // Receive the Packets from the server and switch the contained Frames to the corresponding sinks,
+        // until the transfers are complete and the checksums of the downloads arrived
+        let mut journals_saved = Instant::now();
+        let mut verified = vec![false; streams.len()];
+        loop {
+            while !transmission_complete.iter().all(|&x| x)
+                || self.config.verify
+                    && roles.values().any(|role| matches!(role, Role::Checksum(_)))
+            {
+                // Downloads still running by then get a journal, once the
+                // checksum it records arrived
+                if journals_saved.elapsed() >= JOURNAL_INTERVAL {
+                    for (i, (direction, local, remote)) in streams.iter().enumerate() {
+                        if *direction == Direction::Upload || transmission_complete[i] {
+                            continue;
+                        }
+                        match journals[i].as_mut() {
+                            Some(journal) => save_journal(journal, received[i], local),
+                            None if !requested[i] => {
+                                let checksum_id = ids.allocate()?;
+                                roles.insert(checksum_id, Role::Checksum(i));
+                                requested[i] = true;
+                                let mut command_sink = outgoing.clone();
+                                let checksum_frame = ChecksumFrame::new(checksum_id, remote);
+                                tokio::spawn(async move {
+                                    command_sink.send(checksum_frame.into()).await
+                                });
+                            }
+                            None => {}
+                        }
+                    }
+                    journals_saved = Instant::now();
                 }
-            }
-            let _recv_packet_id = packet.header().packet_id;
-            if _recv_packet_id != last_recv_packet_id + 1 {
-                warn!(
-                    "Received unexpected packet_id from the server, expected {} but got {}",
-                    last_recv_packet_id + 1,
-                    _recv_packet_id
-                );
-                assembler_sink
-                    .send(AckFrame::new(last_recv_packet_id).into())
-                    .await?;
-                assembler_sink
-                    .send(AckFrame::new(last_recv_packet_id).into())
-                    .await?;
-                continue;
-            }
-            last_recv_packet_id = _recv_packet_id;
-            assembler_sink
-                .send(Frame::Ack(AckFrame::new(last_recv_packet_id)))
-                .await?;
 
-            let frames = packet.frames;
-            for frame in frames {
+                // Nothing arriving for a while still saves the journals
+                let frame = match timeout(JOURNAL_INTERVAL, connection.recv()).await {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => return Ok(None),
+                    Err(_) => continue,
+                };
                 let stream_id = frame.stream_id();
-                if stream_id == 0 {
-                    // TODO: handle control frames
-                    debug!(
-                        "Received unhandled control frame. Not implemented: {:?}",
-                        frame
-                    );
-                    continue;
-                }
+                let n = match roles.get(&stream_id) {
+                    Some(&Role::Checksum(i)) => {
+                        roles.remove(&stream_id);
+                        ids.release(stream_id);
+                        let (_, local, remote) = &streams[i];
+                        let checksum = match frame {
+                            Frame::Answer(answer) => Ok(answer.payload().to_vec()),
+                            Frame::Error(e) => {
+                                warn!("Could not get checksum of {:?}: {}", remote, e.message());
+                                Err(e.message().to_string())
+                            }
+                            _ => {
+                                warn!(
+                                    "Unexpected frame on checksum stream {}: {:?}",
+                                    stream_id, frame
+                                );
+                                continue;
+                            }
+                        };
 
-                let n = stream_id as usize;
-                if n - 1 > self.sinks.len() {
-                    warn!(
-                        "Received frame for unknown stream with stream_id: {}. Ignoring it.",
-                        n
-                    );
-                    continue;
-                }
+                        // A continued download starts once the remote file is known to be unchanged
+                        if let Some(journal) = pending[i].take() {
+                            let offset = match checksum.as_ref() == Ok(&journal.checksum) {
+                                true => min(journal.offset, partial_size(local)?),
+                                false => {
+                                    warn!(
+                                        "{:?} changed on the server, downloading it again",
+                                        remote
+                                    );
+                                    0
+                                }
+                            };
+                            // Data past the journaled offset was never confirmed
+                            fs::OpenOptions::new()
+                                .write(true)
+                                .create(true)
+                                .truncate(false)
+                                .open(local)
+                                .and_then(|f| f.set_len(offset))
+                                .context(format!("Failed to truncate file {:?}", local))?;
+                            info!("Continuing {:?} at offset {}", local, offset);
+                            offsets[i] = offset;
+                            received[i] = offset;
+                            start_download(
+                                &mut sinks[i],
+                                &outgoing,
+                                stream_ids[i],
+                                flags,
+                                offset,
+                                local,
+                                remote,
+                            )
+                            .await?;
+                        }
+
+                        if let Some(checksum) =
+                            checksum.as_ref().ok().filter(|_| !transmission_complete[i])
+                        {
+                            let mut journal = Journal {
+                                remote: remote.clone(),
+                                offset: received[i],
+                                checksum: checksum.clone(),
+                                host: self.config.host,
+                                port: self.config.port,
+                            };
+                            save_journal(&mut journal, received[i], local);
+                            journals[i] = Some(journal);
+                        }
+                        checksums[i] = Some(checksum);
+                        continue;
+                    }
+                    Some(&Role::Transfer(i)) => i + 1,
+                    None => {
+                        warn!(
+                            "Received frame for unknown stream with stream_id: {}. Ignoring it.",
+                            stream_id
+                        );
+                        continue;
+                    }
+                };
+                let direction = streams[n - 1].0;
 
                 // Check if it is the last data frame
                 if let Frame::Data(data_frame) = &frame {
+                    received[n - 1] = data_frame.offset() + data_frame.length();
                     if data_frame.length() == 0 {
                         info!("Received last data for stream {}: {:?}", n - 1, data_frame);
                         info!("Transmission complete for stream {}", n - 1);
@@ -302,17 +835,58 @@ impl Client {
                     }
                 }
 
+                // The server confirms each completed upload
+                if let (Direction::Upload, Frame::Answer(_)) = (direction, &frame) {
+                    info!("Upload complete for stream {}", n - 1);
+                    transmission_complete[n - 1] = true;
+                    continue;
+                }
+
+                // A partial file that isn't a prefix of the remote one is downloaded again
                 if let Frame::Error(error_frame) = &frame {
+                    if direction == Direction::Download
+                        && offsets[n - 1] > 0
+                        && error_frame.error_code() == ErrorCode::ChecksumMismatch
+                    {
+                        let (_, local, remote) = &streams[n - 1];
+                        warn!(
+                            "{:?} does not match the remote file, downloading it again",
+                            local
+                        );
+                        // The old handler gives up on the error
+                        let _ = sinks[n - 1].send(frame).await;
+                        offsets[n - 1] = 0;
+                        received[n - 1] = 0;
+                        handlers[n - 1] = Some(
+                            restart_download(
+                                &mut sinks[n - 1],
+                                &outgoing,
+                                &ctx,
+                                stream_id,
+                                flags,
+                                local,
+                                remote,
+                            )
+                            .await?,
+                        );
+                        continue;
+                    }
+
                     warn!(
                         "Received error from server: {}, terminating stream {}",
                         error_frame.message(),
                         error_frame.stream_id()
                     );
-                    self.sinks[n - 1].send(frame.clone()).await?;
-                    self.failed[n - 1] = true;
+                    // The local handler of an upload may have finished already
+                    let result = sinks[n - 1].send(frame.clone()).await;
+                    if direction == Direction::Download {
+                        result?;
+                    }
+                    failed[n - 1] = true;
+                    transmission_complete[n - 1] = true;
                 }
 
-                if self.failed[n - 1] {
+                if failed[n - 1] {
                     warn!(
                         "Got frame for failed stream {} from server, ignoring",
                         n - 1
@@ -320,19 +894,692 @@ impl Client {
                     continue;
                 }
 
-                // Send frame to corresponding sink
-                self.sinks[n - 1].send(frame).await?;
+                // Send frame to corresponding sink, the local handler of an
+                // upload may have sent everything while the server still
+                // extends its window
+                let result = sinks[n - 1].send(frame).await;
+                if direction == Direction::Download {
+                    result?;
+                }
                 debug!("Sent frame to sink {}", n - 1);
             }
+
+            if !self.config.verify {
+                break;
+            }
+
+            // Hash the completed downloads once their handlers flushed them,
+            // corrupt ones are downloaded once more
+            let mut digests = Vec::new();
+            for (i, (direction, local, _)) in streams.iter().enumerate() {
+                if *direction == Direction::Upload || failed[i] || verified[i] {
+                    continue;
+                }
+                let handler = handlers[i].take();
+                let path = local.clone();
+                let digest = tokio::spawn(async move {
+                    if let Some(handler) = handler {
+                        handler.await??;
+                    }
+                    let digest = spawn_blocking(move || {
+                        sha256_digest(BufReader::new(fs::File::open(path)?))
+                    })
+                    .await??;
+                    Ok::<_, anyhow::Error>(digest.as_ref().to_vec())
+                });
+                digests.push((i, digest));
+            }
+            let mut restarted = false;
+            for (i, digest) in digests {
+                let (_, local, remote) = &streams[i];
+                let digest = digest.await.context("Stream handler panicked")?;
+                let expected = checksums[i]
+                    .clone()
+                    .unwrap_or_else(|| Err("No checksum received".into()));
+                let matches = match (expected, digest) {
+                    (Ok(expected), Ok(digest)) => Ok(expected == digest),
+                    (Err(e), _) => Err(anyhow!(e)),
+                    (_, Err(e)) => Err(e),
+                };
+
+                match matches {
+                    Ok(true) => {
+                        info!("Verified {:?}", local);
+                        verified[i] = true;
+                    }
+                    Ok(false) if !redownloaded[i] => {
+                        warn!("{:?} is corrupt, downloading it again", local);
+                        redownloaded[i] = true;
+                        offsets[i] = 0;
+                        received[i] = 0;
+                        transmission_complete[i] = false;
+                        restarted = true;
+                        handlers[i] = Some(
+                            restart_download(
+                                &mut sinks[i],
+                                &outgoing,
+                                &ctx,
+                                stream_ids[i],
+                                flags,
+                                local,
+                                remote,
+                            )
+                            .await?,
+                        );
+                    }
+                    Ok(false) => {
+                        error!("{:?} is corrupt", local);
+                        failed[i] = true;
+                    }
+                    Err(e) => {
+                        error!("Could not verify {:?}: {}", local, e);
+                        failed[i] = true;
+                    }
+                }
+            }
+            if !restarted {
+                break;
+            }
         }
 
-        debug!("Transmission complete. Closing connection...");
-        // Send Exit Frame
-        let mut packet = Packet::new(conn_id, packet_id);
-        packet.add_frame(Frame::Exit(ExitFrame::new()));
-        let bytes = spawn_blocking(move || packet.assemble()).await?;
-        conn.send(&bytes).await.context("Failed to send packet")?;
-        debug!("Sent ExitFrame to server with packet_id {}", packet_id);
+        // Wait for the stream handlers to flush the files and apply their
+        // metadata
+        for handler in handlers.into_iter().flatten() {
+            handler.await.context("Stream handler panicked")??;
+        }
+        // Checksums no journal is waiting for anymore may still be answered,
+        // their ids are only reused once all others were
+        for stream_id in roles.into_keys() {
+            ids.release(stream_id);
+        }
+
+        // Journals are only kept to continue the downloads that failed
+        for (i, (direction, local, _)) in streams.iter().enumerate() {
+            if *direction == Direction::Upload {
+                continue;
+            }
+            match (failed[i], journals[i].as_mut()) {
+                (true, Some(journal)) => save_journal(journal, received[i], local),
+                (true, None) => {}
+                (false, _) => Journal::remove(local)
+                    .context(format!("Failed to remove journal of {:?}", local))?,
+            }
+        }
+        Ok(Some(failed.iter().filter(|&&f| f).count()))
+    }
+}
+
+/// Send a download's WriteFrame to its local handler and the ReadFrame
+/// continuing at `offset` to the server.
+async fn start_download(
+    sink: &mut Sender<Frame>,
+    commands: &Sender<Frame>,
+    stream_id: u16,
+    flags: u8,
+    offset: u64,
+    local: &Path,
+    remote: &Path,
+) -> anyhow::Result<()> {
+    sink.send(Frame::Write(WriteFrame::new(
+        stream_id, 0, offset, 0, local,
+    )))
+    .await?;
+    debug!(
+        "Sent WriteFrame for file: {:?} to stream {}",
+        local, stream_id
+    );
+
+    // Sent from a separate task, the window may fill up before we start
+    // receiving the server's ACKs
+    let read_frame = download_frame(stream_id, flags, offset, local, remote)?;
+    let mut commands = commands.clone();
+    tokio::spawn(async move { commands.send(read_frame.into()).await });
+    Ok(())
+}
+
+/// Download a file again from the beginning with a fresh local handler.
+async fn restart_download(
+    sink: &mut Sender<Frame>,
+    commands: &Sender<Frame>,
+    ctx: &HandlerContext,
+    stream_id: u16,
+    flags: u8,
+    local: &Path,
+    remote: &Path,
+) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+    fs::File::create(local).context(format!("Failed to truncate file {:?}", local))?;
+    let (tx, rx): (Sender<Frame>, Receiver<Frame>) = channel(3);
+    let handler = tokio::spawn(stream_handler(rx, handler_sink(commands), ctx.clone()));
+    *sink = tx;
+    start_download(sink, commands, stream_id, flags, 0, local, remote).await?;
+    Ok(handler)
+}
+
+//a download can still complete without its journal, it just can't be continued,
+//only data that reached the file counts as the handler may still buffer the rest
+fn save_journal(journal: &mut Journal, received: u64, local: &Path) {
+    journal.offset = fs::metadata(local).map_or(0, |m| min(received, m.len()));
+    if let Err(e) = journal.save(local) {
+        warn!("Failed to save journal of {:?}: {}", local, e);
+    }
+}
+
+/// Set the modification time of `local` to `mtime` seconds since the epoch.
+fn set_mtime(local: &Path, mtime: i64) -> io::Result<()> {
+    let mtime = match u64::try_from(mtime) {
+        Ok(secs) => UNIX_EPOCH + Duration::from_secs(secs),
+        Err(_) => UNIX_EPOCH - Duration::from_secs(mtime.unsigned_abs()),
+    };
+    fs::File::options()
+        .write(true)
+        .open(local)?
+        .set_modified(mtime)
+}
+
+/// Size of the partial download at `local`, 0 if it doesn't exist yet.
+///
+/// Missing parent directories are created so the file can be.
+fn partial_size(local: &Path) -> anyhow::Result<u64> {
+    match fs::metadata(local) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if let Some(dir) = local.parent().filter(|d| !d.as_os_str().is_empty()) {
+                fs::create_dir_all(dir).context(format!("Failed to create {:?}", dir))?;
+            }
+            Ok(0)
+        }
+        Err(e) => Err(e).context(format!("Failed to read file {:?}", local)),
+    }
+}
+
+/// ReadFrame requesting `remote` from `offset`, the bytes `local` already
+/// holds up to there are checked by the server before anything is sent.
+fn download_frame(
+    stream_id: u16,
+    flags: u8,
+    offset: u64,
+    local: &Path,
+    remote: &Path,
+) -> anyhow::Result<ReadFrame> {
+    if offset == 0 {
+        return Ok(ReadFrame::new(stream_id, flags, 0, 0, 0, remote));
+    }
+    let file = fs::File::open(local).context(format!("Failed to read file {:?}", local))?;
+    let checksum = prefix_crc32(file, offset)?;
+    Ok(ReadFrame::new(
+        stream_id,
+        flags | ReadFrame::FLAG_RESUME,
+        offset,
+        0,
+        checksum,
+        remote,
+    ))
+}
+
+/// What a sync transfers, the directories it creates and the entries it deletes.
+#[derive(Debug, Default)]
+struct SyncPlan {
+    dirs: Vec<PathBuf>,
+    downloads: Vec<Transfer>,
+    uploads: Vec<Transfer>,
+    deletions: Vec<PathBuf>,
+}
+
+impl fmt::Display for SyncPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for dir in &self.dirs {
+            writeln!(f, "mkdir {}", dir.display())?;
+        }
+        for t in &self.downloads {
+            writeln!(
+                f,
+                "download {} -> {}",
+                t.remote.display(),
+                t.local.display()
+            )?;
+        }
+        for t in &self.uploads {
+            writeln!(f, "upload {} -> {}", t.local.display(), t.remote.display())?;
+        }
+        for path in &self.deletions {
+            writeln!(f, "delete {}", path.display())?;
+        }
         Ok(())
     }
 }
+
+/// Commands answered before the transfers start, like listings.
+struct Requests<'a> {
+    connection: &'a mut Connection,
+    ids: &'a mut StreamIds,
+}
+
+impl Requests<'_> {
+    /// Send one command per path and collect the answers in order.
+    ///
+    /// Listings span several answers up to an empty one, they are joined.
+    async fn exchange<F>(
+        &mut self,
+        paths: &[&Path],
+        command: F,
+    ) -> anyhow::Result<Vec<Result<Vec<u8>, ErrorFrame>>>
+    where
+        F: Fn(u16, &Path) -> Frame,
+    {
+        let mut stream_ids = Vec::new();
+        for _ in paths {
+            stream_ids.push(self.ids.allocate()?);
+        }
+        let commands: Vec<Frame> = paths
+            .iter()
+            .zip(&stream_ids)
+            .map(|(path, &id)| command(id, path))
+            .collect();
+        let listing = commands
+            .iter()
+            .map(|c| matches!(c, Frame::List(_)))
+            .collect::<Vec<_>>();
+
+        // Send them from a separate task, the window may fill up before we
+        // start receiving the server's ACKs
+        let mut command_sink = self.connection.sender();
+        tokio::spawn(async move {
+            for frame in commands {
+                command_sink.send(frame).await?;
+            }
+            Ok::<(), futures::channel::mpsc::SendError>(())
+        });
+
+        let mut answers: Vec<Option<Result<Vec<u8>, ErrorFrame>>> =
+            paths.iter().map(|_| None).collect();
+        let mut partial = vec![Vec::new(); paths.len()];
+        while answers.iter().any(Option::is_none) {
+            let Some(frame) = self.connection.recv().await else {
+                return Err(anyhow!("The server closed the connection"));
+            };
+            let i = stream_ids.iter().position(|&id| id == frame.stream_id());
+            let Some(i) = i.filter(|&i| answers[i].is_none()) else {
+                warn!("Unexpected frame for a request: {:?}", frame);
+                continue;
+            };
+            match frame {
+                Frame::Answer(answer) if !listing[i] => {
+                    answers[i] = Some(Ok(answer.payload().to_vec()))
+                }
+                Frame::Answer(answer) if answer.payload().is_empty() => {
+                    answers[i] = Some(Ok(std::mem::take(&mut partial[i])))
+                }
+                Frame::Answer(answer) => partial[i].extend_from_slice(answer.payload()),
+                Frame::Error(e) => answers[i] = Some(Err(e)),
+                _ => warn!("Unexpected frame for a request: {:?}", frame),
+            }
+        }
+        for id in stream_ids {
+            self.ids.release(id);
+        }
+        Ok(answers.into_iter().flatten().collect())
+    }
+
+    async fn list(&mut self, paths: &[&Path]) -> anyhow::Result<Vec<Vec<ListEntry>>> {
+        let answers = self
+            .exchange(paths, |id, path| ListFrame::new(id, path).into())
+            .await?;
+        answers
+            .into_iter()
+            .zip(paths)
+            .map(|(answer, path)| match answer {
+                Ok(listing) => ListEntry::parse_answer(&listing)
+                    .context(format!("Invalid listing of {:?}", path)),
+                Err(e) => Err(anyhow!("Could not list {:?}: {}", path, e.message())),
+            })
+            .collect()
+    }
+
+    // entry of the path, None if there is no such file or directory
+    async fn stat(&mut self, path: &Path) -> anyhow::Result<Option<ListEntry>> {
+        let answer = self
+            .exchange(&[path], |id, path| StatFrame::new(id, path).into())
+            .await?
+            .pop();
+        match answer {
+            Some(Ok(entry)) => Ok(ListEntry::parse_answer(&entry)
+                .context(format!("Invalid answer to stat of {:?}", path))?
+                .pop()),
+            Some(Err(e)) if e.error_code() == ErrorCode::NotFound => Ok(None),
+            Some(Err(e)) => Err(anyhow!("Could not stat {:?}: {}", path, e.message())),
+            None => Ok(None),
+        }
+    }
+
+    // SHA-256 of the files, None for those the server couldn't read
+    async fn checksums(&mut self, paths: &[&Path]) -> anyhow::Result<Vec<Option<Vec<u8>>>> {
+        let answers = self
+            .exchange(paths, |id, path| ChecksumFrame::new(id, path).into())
+            .await?;
+        Ok(answers
+            .into_iter()
+            .zip(paths)
+            .map(|(answer, path)| match answer {
+                Ok(checksum) => Some(checksum),
+                Err(e) => {
+                    warn!("Could not get checksum of {:?}: {}", path, e.message());
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+// Local stream handlers report their own completion and errors like the
+// server's do, only the frames for the server are passed on
+fn handler_sink(
+    commands: &Sender<Frame>,
+) -> impl Sink<Frame, Error = SendError> + Unpin + Send + 'static {
+    commands.clone().with_flat_map(|frame: Frame| {
+        let forward = match &frame {
+            Frame::Error(error_frame) => {
+                warn!(
+                    "Received error from writer: {} for stream {}, ignoring",
+                    error_frame.message(),
+                    error_frame.stream_id()
+                );
+                false
+            }
+            Frame::Answer(_) => false,
+            _ => true,
+        };
+        futures::stream::iter(forward.then_some(Ok(frame)))
+    })
+}
+
+/// UDP socket to talk to the server at `addr` on, and the server's address.
+pub(crate) async fn server_socket(
+    addr: impl ToSocketAddrs,
+) -> anyhow::Result<(UdpSocket, SocketAddr)> {
+    let server = lookup_host(addr)
+        .await
+        .map_err(|e| anyhow!("Failed to resolve server address: {}", e))?
+        .next()
+        .ok_or_else(|| anyhow!("Failed to resolve server address"))?;
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .map_err(|e| anyhow!("Failed to bind socket: {}", e))?;
+    Ok((socket, server))
+}
+
+/// Packets to and from the server at `server` over `socket`, through the loss
+/// simulation.
+///
+/// The packets follow the server to another address once it answered the
+/// challenge sent there, see [`PeerAddress`], packets from anywhere else are
+/// dropped. Challenges and their answers are handled here and don't reach
+/// the connection. The socket is served until the connection drops its
+/// packet sink.
+pub(crate) fn transport(
+    socket: UdpSocket,
+    server: SocketAddr,
+    mut loss_sim: Option<LossSimulation>,
+) -> (Receiver<Packet>, Sender<Packet>) {
+    let (mut input, input_rx) = channel(128);
+    let (output, mut output_rx) = channel::<Packet>(32);
+    tokio::spawn(async move {
+        let mut recv_buf = [0; 2048];
+        let mut server = PeerAddress::new(server);
+        loop {
+            tokio::select! {
+                packet = output_rx.next() => {
+                    let Some(packet) = packet else {
+                        return;
+                    };
+                    if loss_sim.as_mut().is_some_and(|l| l.drop_packet()) {
+                        warn!("Simulated loss of sent packet {} occurred!", packet.packet_id());
+                        continue;
+                    }
+                    if let Err(e) = socket.send_to(&packet.assemble(), server.addr()).await {
+                        error!("Failed to send packet to {}: {}", server.addr(), e);
+                    }
+                }
+                received = socket.recv_from(&mut recv_buf) => {
+                    let (packet, from) = match received_packet(received, &recv_buf) {
+                        Ok(received) => received,
+                        Err(e) => {
+                            error!("Failed to receive packet from server: {}", e);
+                            continue;
+                        }
+                    };
+                    if loss_sim.as_mut().is_some_and(|l| l.drop_packet()) {
+                        warn!("Simulated loss of received packet {} occurred!", packet.packet_id());
+                        continue;
+                    }
+                    // The server validates the client's address the same way
+                    if let Some(response) = migration::respond(&packet) {
+                        if let Err(e) = socket.send_to(&response.assemble(), from).await {
+                            error!("Failed to answer challenge of {}: {}", from, e);
+                        }
+                    }
+                    match server.on_receive(&packet, from) {
+                        Origin::Current => {}
+                        Origin::Migrated => info!("Server migrated to {}", from),
+                        // Dropped like a lost packet, the server sends it again once it moved
+                        Origin::Unvalidated => {
+                            debug!("Dropping packet from unvalidated address {}", from);
+                            if let Some((to, challenge)) = server.challenge() {
+                                let probe = migration::probe(packet.connection_id(), challenge);
+                                if let Err(e) = socket.send_to(&probe.assemble(), to).await {
+                                    error!("Failed to send challenge to {}: {}", to, e);
+                                }
+                            }
+                            continue;
+                        }
+                    }
+                    if migration::is_probe(&packet) {
+                        continue;
+                    }
+                    //a full input is like a lost packet
+                    if let Err(e) = input.try_send(packet) {
+                        if e.is_disconnected() {
+                            return;
+                        }
+                        warn!("Connection input full, packet dropped");
+                    }
+                }
+            }
+        }
+    });
+    (input_rx, output)
+}
+
+// the packet received into `buf` and where it came from
+fn received_packet(
+    received: io::Result<(usize, SocketAddr)>,
+    buf: &[u8],
+) -> anyhow::Result<(Packet, SocketAddr)> {
+    let (size, from) = received?;
+    Ok((Packet::parse_buf(&buf[..size])?, from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ServerBuilder;
+
+    #[tokio::test]
+    async fn test_put_existing() {
+        let dir = "client_put_test";
+        let _ = fs::remove_dir_all(dir);
+        let root = Path::new(dir).join("root");
+        fs::create_dir_all(&root).unwrap();
+        let local = Path::new(dir).join("new.txt");
+        fs::write(&local, b"the new content").unwrap();
+
+        //both modes replace the file on the server
+        for atomic_writes in [false, true] {
+            fs::write(root.join("a.txt"), b"old content, longer than the new").unwrap();
+            let server = ServerBuilder::new()
+                .bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+                .root(&root)
+                .atomic_writes(atomic_writes)
+                .spawn()
+                .await
+                .unwrap();
+            let port = server.local_addr().port();
+            let mut config = ClientConfig::new(Ipv4Addr::LOCALHOST, port, Vec::new(), None);
+            config
+                .uploads
+                .push(Transfer::new(local.clone(), PathBuf::from("a.txt")));
+            Client::new(config).start().await.unwrap();
+            assert_eq!(fs::read(root.join("a.txt")).unwrap(), b"the new content");
+            server.shutdown().await.unwrap();
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sync_upload_changed() {
+        let dir = "client_sync_test";
+        let _ = fs::remove_dir_all(dir);
+        let local = Path::new(dir).join("local");
+        let root = Path::new(dir).join("root");
+        fs::create_dir_all(&local).unwrap();
+        fs::create_dir_all(root.join("tree")).unwrap();
+        fs::write(local.join("changed.txt"), b"the new content").unwrap();
+        fs::write(root.join("tree/changed.txt"), b"old content").unwrap();
+        let server = ServerBuilder::new()
+            .bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .root(&root)
+            .spawn()
+            .await
+            .unwrap();
+
+        //the changed file is replaced on the server
+        let port = server.local_addr().port();
+        let mut config = ClientConfig::new(Ipv4Addr::LOCALHOST, port, Vec::new(), None);
+        config.syncs.push(SyncTransfer::new(
+            local.clone(),
+            PathBuf::from("tree"),
+            SyncDirection::Upload,
+            false,
+        ));
+        Client::new(config).start().await.unwrap();
+        assert_eq!(
+            fs::read(root.join("tree/changed.txt")).unwrap(),
+            b"the new content"
+        );
+
+        server.shutdown().await.unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_stream_ids() {
+        let mut ids = StreamIds::default();
+        assert_eq!(ids.allocate().unwrap(), 1);
+        assert_eq!(ids.allocate().unwrap(), 2);
+        ids.release(1);
+        //released ids are only reused once all others were used
+        for id in 3..=u16::MAX {
+            assert_eq!(ids.allocate().unwrap(), id);
+        }
+        assert_eq!(ids.allocate().unwrap(), 1);
+        assert!(ids.allocate().is_err());
+    }
+
+    #[test]
+    fn test_parse_download() {
+        let download = |spec, dir: Option<&str>| Transfer::download(spec, dir.map(Path::new));
+        assert_eq!(
+            download("/var/log/app.log", None).unwrap(),
+            Transfer::new("/var/log/app.log".into(), "/var/log/app.log".into())
+        );
+        assert_eq!(
+            download("/var/log/app.log:app-1.log", None).unwrap(),
+            Transfer::new("app-1.log".into(), "/var/log/app.log".into())
+        );
+        assert_eq!(
+            download("/var/log/app.log", Some("logs")).unwrap(),
+            Transfer::new("logs/app.log".into(), "/var/log/app.log".into())
+        );
+        assert_eq!(
+            download("/var/log/app.log:a/app.log", Some("logs")).unwrap(),
+            Transfer::new("logs/a/app.log".into(), "/var/log/app.log".into())
+        );
+        //escaped colons stay in the remote path, the local one takes the rest
+        assert_eq!(
+            download("logs/12\\:00.log:12\\:00.log", None).unwrap(),
+            Transfer::new("12:00.log".into(), "logs/12:00.log".into())
+        );
+        assert_eq!(
+            download("logs/12\\:00.log:a:b.log", None).unwrap(),
+            Transfer::new("a:b.log".into(), "logs/12:00.log".into())
+        );
+        assert!(download(":app.log", None).is_err());
+        assert!(download("app.log:", None).is_err());
+        assert!(download("/", Some("logs")).is_err());
+    }
+
+    #[test]
+    fn test_expand_glob() {
+        let glob = GlobTransfer::download("data/2026-10-*/*.parquet:out", None).unwrap();
+        assert_eq!(glob.local, PathBuf::from("out"));
+        assert_eq!(
+            GlobTransfer::download("data/*.csv", None).unwrap().local,
+            PathBuf::from("data")
+        );
+        assert_eq!(
+            GlobTransfer::download("data/*.csv", Some(Path::new("logs")))
+                .unwrap()
+                .local,
+            PathBuf::from("logs")
+        );
+        let entry = |path: &str, is_dir| ListEntry {
+            path: path.into(),
+            is_dir,
+            size: 0,
+            mtime: 0,
+        };
+        assert_eq!(
+            glob.expand(&[
+                entry("data/2026-10-01/a.parquet", false),
+                entry("data/2026-10-02.parquet", true),
+                entry("other/b.parquet", false),
+                entry("data/../c.parquet", false),
+            ]),
+            vec![Transfer::new(
+                "out/2026-10-01/a.parquet".into(),
+                "data/2026-10-01/a.parquet".into()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_expand_tree() {
+        let entry = |path: &str, is_dir| ListEntry {
+            path: path.into(),
+            is_dir,
+            size: 0,
+            mtime: 0,
+        };
+        let tree = TreeTransfer::new(
+            "local".into(),
+            "remote".into(),
+            Filter::new(vec!["*.csv".parse().unwrap()], vec!["tmp".parse().unwrap()]),
+        );
+        let (dirs, files) = tree.expand(&[
+            entry("a", true),
+            entry("a/b.csv", false),
+            entry("a/c.txt", false),
+            entry("tmp", true),
+            entry("tmp/d.csv", false),
+            entry("../e.csv", false),
+            entry("/f.csv", false),
+        ]);
+        assert_eq!(dirs, vec![PathBuf::from("a")]);
+        assert_eq!(
+            files,
+            vec![Transfer::new(
+                "local/a/b.csv".into(),
+                "remote/a/b.csv".into()
+            )]
+        );
+    }
+}
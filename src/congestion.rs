@@ -0,0 +1,85 @@
+use std::cmp::max;
+
+/// TCP Reno style congestion window in bytes.
+///
+/// In slow start the window grows by the acknowledged bytes, afterwards by
+/// about one segment per window. A loss halves it, a timeout falls back to
+/// slow start from a single segment. It never shrinks below one segment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CongestionWindow {
+    size: usize,
+    threshold: usize,
+    segment: usize,
+}
+
+impl CongestionWindow {
+    pub fn new(initial: usize, segment: usize) -> Self {
+        CongestionWindow {
+            size: max(initial, segment),
+            threshold: usize::MAX,
+            segment,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn in_slow_start(&self) -> bool {
+        self.size < self.threshold
+    }
+
+    pub fn on_ack(&mut self, acked: usize) {
+        let growth = match self.in_slow_start() {
+            true => acked,
+            false => max(self.segment * acked / self.size, 1),
+        };
+        self.size = self.size.saturating_add(growth);
+    }
+
+    /// A packet was lost but later ones arrived, e.g. after a duplicate ACK.
+    pub fn on_loss(&mut self) {
+        self.threshold = max(self.size / 2, self.segment);
+        self.size = self.threshold;
+    }
+
+    /// Nothing was acknowledged in time.
+    pub fn on_timeout(&mut self) {
+        self.threshold = max(self.size / 2, 2 * self.segment);
+        self.size = self.segment;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_congestion_window() {
+        let mut cwnd = CongestionWindow::new(4096, 1024);
+        cwnd.on_ack(2048);
+        assert_eq!(cwnd.size(), 6144);
+        assert!(cwnd.in_slow_start());
+
+        cwnd.on_loss();
+        assert_eq!(cwnd.size(), 3072);
+        assert!(!cwnd.in_slow_start());
+        cwnd.on_ack(3072);
+        assert_eq!(cwnd.size(), 4096);
+
+        //repeated losses never close the window
+        for _ in 0..20 {
+            cwnd.on_loss();
+        }
+        assert_eq!(cwnd.size(), 1024);
+        cwnd.on_ack(1);
+        assert_eq!(cwnd.size(), 1025);
+
+        cwnd.on_timeout();
+        assert_eq!(cwnd.size(), 1024);
+        assert!(cwnd.in_slow_start());
+        cwnd.on_ack(1024);
+        assert_eq!(cwnd.size(), 2048);
+        assert!(!cwnd.in_slow_start());
+    }
+}
@@ -0,0 +1,698 @@
+use crate::congestion::CongestionWindow;
+use crate::wire::{
+    AckFrame, ConnIdChangeFrame, FlowControlFrame, Frame, Packet, Size, MAX_PACKET_SIZE,
+};
+use anyhow::{anyhow, Context};
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use log::{debug, warn};
+use rand::Rng;
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, timeout, Instant};
+
+//bytes in flight until the peer announces its receive window
+const INITIAL_WINDOW: usize = 2048;
+//bytes of received frames buffered until they are taken, announced to the peer
+const RECEIVE_WINDOW: u32 = 32768;
+//congestion window to start with
+const INITIAL_CWND: usize = 4096;
+//time to wait for further frames to fill a packet
+const COALESCE_TIMEOUT: Duration = Duration::from_micros(100);
+//frames the streams may queue before they have to wait for the window
+const FRAME_BUFFER: usize = 16;
+
+/// How long a connection waits for the peer before it gives up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Time to wait for an ACK before retransmitting, or for the answer to a
+    /// connection request.
+    pub ack: Duration,
+    /// Timeouts in a row after which the peer is considered gone.
+    pub max_retries: u32,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            ack: Duration::from_millis(2000),
+            max_retries: 4,
+        }
+    }
+}
+
+/// One end of a connection, used by the client and the server alike.
+///
+/// The frames of all streams are packed into sequenced packets, which are kept
+/// until the peer acknowledges them and resent from the first unacknowledged
+/// one after a duplicate ACK or a timeout. No more bytes are in flight than
+/// both the peer's flow window and the congestion window allow. Received
+/// packets are acknowledged one by one in unsequenced packets, and the stream
+/// frames of those arriving in order are handed out; out of order ones are
+/// answered with a duplicate ACK. Every ACK announces the space left for
+/// frames not yet taken with [`Connection::recv`], a packet that doesn't fit
+/// is dropped like a lost one.
+///
+/// The client proposes the connection id, the server answers under it or
+/// moves the connection to another one if it is taken. The server may change
+/// the id later on as well, packets under the old one are still accepted.
+///
+/// The connection ends when the peer sends an Exit or stops responding. It
+/// also ends once it sent an Exit itself or every [`Sender`] of it is dropped,
+/// as soon as the peer confirmed the rest; frames sent after that are lost.
+pub struct Connection {
+    id: u32,
+    sender: Sender<Frame>,
+    receiver: Receiver<Frame>,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl Connection {
+    /// Open a connection, repeating the request until the peer answers.
+    ///
+    /// The request proposes a random connection id, the answer is the peer's
+    /// first packet and carries the proposal or the id assigned instead.
+    pub async fn connect<I, O>(input: I, output: O) -> anyhow::Result<Self>
+    where
+        I: Stream<Item = Packet> + Unpin + Send + 'static,
+        O: Sink<Packet> + Unpin + Send + 'static,
+        O::Error: std::error::Error + Send + Sync + 'static,
+    {
+        Self::connect_with(input, output, Timeouts::default()).await
+    }
+
+    /// Like [`Connection::connect`], giving up on the peer after `timeouts`.
+    pub async fn connect_with<I, O>(
+        mut input: I,
+        mut output: O,
+        timeouts: Timeouts,
+    ) -> anyhow::Result<Self>
+    where
+        I: Stream<Item = Packet> + Unpin + Send + 'static,
+        O: Sink<Packet> + Unpin + Send + 'static,
+        O::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let proposal = rand::thread_rng().gen_range(1..=u32::MAX);
+        let mut request = Packet::new(0, 1);
+        request.add_frame(ConnIdChangeFrame::new(0, proposal).into());
+        let mut attempts = 0;
+        let answer = loop {
+            output
+                .send(request.clone())
+                .await
+                .context("Failed to send connection request")?;
+            match timeout(timeouts.ack, input.next()).await {
+                Ok(Some(packet)) if packet.connection_id() != 0 => break packet,
+                Ok(Some(_)) => {
+                    return Err(anyhow!("Failed to establish connection, received ConnID 0"))
+                }
+                Ok(None) => return Err(anyhow!("Failed to establish connection, input closed")),
+                Err(_) if attempts < timeouts.max_retries => {
+                    warn!("No answer to the connection request, sending it again");
+                    attempts += 1;
+                }
+                Err(_) => return Err(anyhow!("Failed to establish connection, no answer")),
+            }
+        };
+        if answer.packet_id() != 1 {
+            warn!(
+                "Connection established with unexpected packet_id {}",
+                answer.packet_id()
+            );
+        }
+
+        //a taken proposal is answered under the id the server chose instead
+        let id = answer.connection_id();
+        let mut link = Link::new(id, output, 2, answer.packet_id(), timeouts);
+        link.follows_peer = true;
+        let input = futures::stream::iter(Some(answer)).chain(input);
+        Self::start(link, id, input, None).await
+    }
+
+    /// Accept the connection request that is the first packet of `input`.
+    ///
+    /// It is answered with the first packet under the connection id `id`,
+    /// which differs from the proposal of the client if that was taken.
+    pub async fn accept<I, O>(
+        mut input: I,
+        output: O,
+        id: u32,
+        timeouts: Timeouts,
+    ) -> anyhow::Result<Self>
+    where
+        I: Stream<Item = Packet> + Unpin + Send + 'static,
+        O: Sink<Packet> + Unpin + Send + 'static,
+        O::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let request = input
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("Input closed before the connection request"))?;
+        //the change tells the client its proposal was taken, it is answered under `id` nonetheless
+        let change = proposed_id(&request)
+            .filter(|&proposal| proposal != id)
+            .map(|proposal| ConnIdChangeFrame::new(proposal, id));
+        let link = Link::new(id, output, 1, request.packet_id() + 1, timeouts);
+        Self::start(link, id, input, change).await
+    }
+
+    async fn start<I, O>(
+        link: Link<O>,
+        id: u32,
+        input: I,
+        change: Option<ConnIdChangeFrame>,
+    ) -> anyhow::Result<Self>
+    where
+        I: Stream<Item = Packet> + Unpin + Send + 'static,
+        O: Sink<Packet> + Unpin + Send + 'static,
+        O::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let (mut sender, frames) = channel(FRAME_BUFFER);
+        let (deliver, receiver) = channel(FRAME_BUFFER);
+        if let Some(change) = change {
+            sender.send(change.into()).await?;
+        }
+        sender
+            .send(FlowControlFrame::new(RECEIVE_WINDOW).into())
+            .await?;
+        let task = tokio::spawn(link.run(input, frames, deliver));
+        Ok(Connection {
+            id,
+            sender,
+            receiver,
+            task,
+        })
+    }
+
+    /// Id the connection was established with, the server may change it later.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Handle to send frames to the peer with, for any stream.
+    pub fn sender(&self) -> Sender<Frame> {
+        self.sender.clone()
+    }
+
+    /// The next frame the peer sent on a stream, None once the connection ended.
+    pub async fn recv(&mut self) -> Option<Frame> {
+        self.receiver.next().await
+    }
+
+    /// Wait for the connection to end, after all frames sent were confirmed.
+    ///
+    /// Unless an Exit was sent, every other handle from [`Connection::sender`]
+    /// has to be dropped first, or the connection has to have ended already.
+    pub async fn close(self) -> anyhow::Result<()> {
+        //frames still arriving are of no use to anyone now
+        let Connection {
+            sender,
+            receiver,
+            task,
+            ..
+        } = self;
+        drop((sender, receiver));
+        task.await.context("Connection task panicked")?
+    }
+}
+
+/// The connection id a client proposed in its connection request, if any.
+pub fn proposed_id(request: &Packet) -> Option<u32> {
+    request
+        .frames
+        .iter()
+        .filter_map(Frame::conn_id_change)
+        .find(|change| change.old_cid() == 0 && change.new_cid() != 0)
+        .map(|change| change.new_cid())
+}
+
+/// Reliable delivery of the packets of one connection in both directions.
+struct Link<O> {
+    id: u32,
+    //id before the last change, the peer may still use it
+    retired: Option<u32>,
+    //only the server assigns ids, so only the client follows changes of the peer
+    follows_peer: bool,
+    output: O,
+    next_packet_id: u32,
+    //next packet id expected from the peer
+    expected: u32,
+    unacked: VecDeque<Packet>,
+    //packets at the front of unacked sent since the last rewind, and their bytes
+    sent: usize,
+    in_flight: usize,
+    window: usize,
+    cwnd: CongestionWindow,
+    last_ack: u32,
+    rewound: bool,
+    timeouts: u32,
+    deadline: Instant,
+    limits: Timeouts,
+    //stream frames received in order but not handed out yet, and their bytes
+    received: VecDeque<Frame>,
+    buffered: usize,
+    //receive window last announced to the peer
+    announced: usize,
+}
+
+impl<O> Link<O>
+where
+    O: Sink<Packet> + Unpin,
+    O::Error: std::error::Error + Send + Sync + 'static,
+{
+    fn new(id: u32, output: O, next_packet_id: u32, expected: u32, limits: Timeouts) -> Self {
+        Link {
+            id,
+            retired: None,
+            follows_peer: false,
+            output,
+            next_packet_id,
+            expected,
+            unacked: VecDeque::new(),
+            sent: 0,
+            in_flight: 0,
+            window: INITIAL_WINDOW,
+            cwnd: CongestionWindow::new(INITIAL_CWND, MAX_PACKET_SIZE),
+            last_ack: 0,
+            rewound: false,
+            timeouts: 0,
+            deadline: Instant::now(),
+            limits,
+            received: VecDeque::new(),
+            buffered: 0,
+            announced: RECEIVE_WINDOW as usize,
+        }
+    }
+
+    fn window_open(&self) -> bool {
+        self.in_flight < min(self.window, self.cwnd.size())
+    }
+
+    fn free(&self) -> usize {
+        (RECEIVE_WINDOW as usize).saturating_sub(self.buffered)
+    }
+
+    async fn run(
+        mut self,
+        mut input: impl Stream<Item = Packet> + Unpin,
+        mut frames: Receiver<Frame>,
+        mut deliver: Sender<Frame>,
+    ) -> anyhow::Result<()> {
+        let mut pending = None;
+        let mut closed = false;
+
+        loop {
+            while self.sent < self.unacked.len() && self.window_open() {
+                let packet = self.unacked[self.sent].clone();
+                self.in_flight += packet.size();
+                self.sent += 1;
+                self.transmit(packet).await?;
+            }
+            if closed && pending.is_none() && self.unacked.is_empty() {
+                return Ok(());
+            }
+            //a frame that didn't fit into the last packet starts the next one
+            if self.sent == self.unacked.len() && self.window_open() {
+                if let Some(frame) = pending.take() {
+                    self.queue(frame, &mut frames, &mut pending, &mut closed)
+                        .await;
+                    continue;
+                }
+            }
+
+            tokio::select! {
+                biased;
+                ready = poll_fn(|cx| deliver.poll_ready(cx)), if !self.received.is_empty() => {
+                    let frame = self.received.pop_front().unwrap();
+                    self.buffered -= frame.size();
+                    //nobody may be listening anymore, e.g. while the connection closes
+                    if ready.and_then(|_| deliver.start_send(frame)).is_err() {
+                        self.received.clear();
+                        self.buffered = 0;
+                    }
+                    //the peer may wait for the window to open
+                    if self.free() >= self.announced + RECEIVE_WINDOW as usize / 2 {
+                        self.announce_window().await?;
+                    }
+                }
+                packet = input.next() => {
+                    let Some(packet) = packet else {
+                        debug!("Input of connection {} closed", self.id);
+                        self.flush(&mut deliver).await;
+                        return Ok(());
+                    };
+                    if self.receive(packet, &deliver).await? {
+                        self.flush(&mut deliver).await;
+                        return Ok(());
+                    }
+                }
+                _ = sleep_until(self.deadline), if !self.unacked.is_empty() => {
+                    self.timeouts += 1;
+                    if self.timeouts > self.limits.max_retries {
+                        if closed {
+                            warn!("Peer didn't confirm the end of connection {}", self.id);
+                            return Ok(());
+                        }
+                        return Err(anyhow!("Too many timeouts, the peer stopped responding"));
+                    }
+                    //the peer's window may have opened with a lost update, it answers with its window
+                    if self.sent == 0 && !self.window_open() {
+                        debug!("Receive window of the peer closed, probing it");
+                        let packet = self.unacked[0].clone();
+                        self.in_flight += packet.size();
+                        self.sent = 1;
+                        self.deadline = Instant::now() + self.limits.ack;
+                        self.transmit(packet).await?;
+                        continue;
+                    }
+                    warn!(
+                        "Timeout waiting for ACK, resending {} packets",
+                        self.unacked.len()
+                    );
+                    self.cwnd.on_timeout();
+                    self.rewind();
+                }
+                frame = frames.next(), if !closed && pending.is_none() && self.sent == self.unacked.len() && self.window_open() => {
+                    match frame {
+                        Some(frame) => {
+                            self.queue(frame, &mut frames, &mut pending, &mut closed)
+                                .await
+                        }
+                        None => closed = true,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle a packet of the peer, true if it ended the connection.
+    async fn receive(&mut self, packet: Packet, deliver: &Sender<Frame>) -> anyhow::Result<bool> {
+        if packet.connection_id() != self.id && Some(packet.connection_id()) != self.retired {
+            //e.g. the answer to a repeated connection request
+            debug!("Ignoring packet of connection {}", packet.connection_id());
+            return Ok(false);
+        }
+        if packet.packet_id() == 0 {
+            //unsequenced packets may only carry ACKs and windows, which need no ordering
+            for frame in packet.frames {
+                match frame {
+                    Frame::Ack(ack) => self.on_ack(ack.packet_id()),
+                    Frame::FlowControl(f) => {
+                        //the peer is alive even if it takes nothing right now
+                        self.window = f.window_size() as usize;
+                        self.timeouts = 0;
+                    }
+                    _ => warn!("Discarding unsequenced {:?}", frame),
+                }
+            }
+            return Ok(false);
+        }
+        if packet.packet_id() != self.expected {
+            debug!(
+                "Expected packet {} but got {}, sending double ACK",
+                self.expected,
+                packet.packet_id()
+            );
+            self.ack(self.expected.saturating_sub(1)).await?;
+            return Ok(false);
+        }
+        let size = packet
+            .frames
+            .iter()
+            .filter(|frame| frame.stream_id() != 0)
+            .map(Size::size)
+            .sum::<usize>();
+        //the peer may send while less than the window is in flight, so a packet more fits
+        if self.buffered + size > RECEIVE_WINDOW as usize + MAX_PACKET_SIZE {
+            debug!(
+                "No room for packet {}, {} bytes buffered",
+                packet.packet_id(),
+                self.buffered
+            );
+            self.announce_window().await?;
+            return Ok(false);
+        }
+        self.expected += 1;
+        //the ACK is sent under the new id already
+        for change in packet.frames.iter().filter_map(Frame::conn_id_change) {
+            self.on_id_change(change);
+        }
+        self.ack(packet.packet_id()).await?;
+
+        for frame in packet.frames {
+            match frame {
+                Frame::Ack(ack) => self.on_ack(ack.packet_id()),
+                Frame::FlowControl(f) => {
+                    debug!("Received FlowControlFrame with size {}", f.window_size());
+                    self.window = f.window_size() as usize;
+                }
+                Frame::Exit(_) => {
+                    debug!("Received ExitFrame, terminating connection {}", self.id);
+                    return Ok(true);
+                }
+                Frame::ConnIdChange(_) => {}
+                frame if frame.stream_id() == 0 => {
+                    warn!("Discarding unhandled control frame {:?}", frame)
+                }
+                //nobody may be listening anymore, e.g. while the connection closes
+                _ if deliver.is_closed() => {}
+                frame => {
+                    self.buffered += frame.size();
+                    self.received.push_back(frame);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    //the frames received before the end are still handed out
+    async fn flush(&mut self, deliver: &mut Sender<Frame>) {
+        for frame in self.received.drain(..) {
+            if deliver.send(frame).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    fn on_ack(&mut self, packet_id: u32) {
+        if packet_id >= self.next_packet_id {
+            warn!("Ignoring ACK for packet ID {} that wasn't sent", packet_id);
+            return;
+        }
+        if packet_id > self.last_ack {
+            debug!("Received ACK for packet ID {}", packet_id);
+            self.last_ack = packet_id;
+            let mut acked = 0;
+            while self
+                .unacked
+                .front()
+                .is_some_and(|p| p.packet_id() <= packet_id)
+            {
+                let packet = self.unacked.pop_front().unwrap();
+                acked += packet.size();
+                if self.sent > 0 {
+                    self.sent -= 1;
+                    self.in_flight -= packet.size();
+                }
+            }
+            self.cwnd.on_ack(acked);
+            self.rewound = false;
+            self.timeouts = 0;
+            self.deadline = Instant::now() + self.limits.ack;
+        } else if !self.rewound && !self.unacked.is_empty() {
+            //duplicate ACK, the peer missed the packet after it
+            debug!(
+                "Double ACK for ID {}, resending {} packets",
+                packet_id,
+                self.unacked.len()
+            );
+            self.cwnd.on_loss();
+            self.rewind();
+            self.rewound = true;
+        }
+    }
+
+    fn on_id_change(&mut self, change: &ConnIdChangeFrame) {
+        if !self.follows_peer {
+            warn!("Ignoring change of connection id {} by the client", self.id);
+            return;
+        }
+        //e.g. a retransmission of the change
+        if change.old_cid() != self.id {
+            return;
+        }
+        debug!("Connection {} changed id to {}", self.id, change.new_cid());
+        self.retired = Some(self.id);
+        self.id = change.new_cid();
+        //the server forgets the old id, packets not yet confirmed are resent under the new one
+        for packet in &mut self.unacked {
+            packet.set_connection_id(self.id);
+        }
+    }
+
+    fn rewind(&mut self) {
+        self.sent = 0;
+        self.in_flight = 0;
+        self.deadline = Instant::now() + self.limits.ack;
+    }
+
+    /// Pack `first` and the frames following it in time into the next packet.
+    async fn queue(
+        &mut self,
+        first: Frame,
+        frames: &mut Receiver<Frame>,
+        pending: &mut Option<Frame>,
+        closed: &mut bool,
+    ) {
+        let mut packet = Packet::new(self.id, self.next_packet_id);
+        //nothing is sent after an Exit, the peer is gone once it confirmed it
+        *closed |= matches!(first, Frame::Exit(_));
+        //the change of the id is sent under the old one, the packets after it under the new one
+        let mut new_id = self.changed_id(&first);
+        packet.add_frame(first);
+        self.next_packet_id += 1;
+        while !*closed {
+            match timeout(COALESCE_TIMEOUT, frames.next()).await {
+                Ok(Some(frame)) if packet.size() + frame.size() > MAX_PACKET_SIZE => {
+                    *pending = Some(frame);
+                    break;
+                }
+                Ok(Some(frame)) => {
+                    *closed |= matches!(frame, Frame::Exit(_));
+                    new_id = new_id.or(self.changed_id(&frame));
+                    packet.add_frame(frame)
+                }
+                Ok(None) => *closed = true,
+                Err(_) => break,
+            }
+        }
+        if self.unacked.is_empty() {
+            self.deadline = Instant::now() + self.limits.ack;
+        }
+        self.unacked.push_back(packet);
+        if let Some(new_id) = new_id {
+            debug!("Changing id of connection {} to {}", self.id, new_id);
+            self.retired = Some(self.id);
+            self.id = new_id;
+        }
+    }
+
+    fn changed_id(&self, frame: &Frame) -> Option<u32> {
+        frame
+            .conn_id_change()
+            .filter(|change| change.old_cid() == self.id)
+            .map(|change| change.new_cid())
+    }
+
+    //ACKs are not sequenced, so they are neither acknowledged nor retransmitted
+    async fn ack(&mut self, packet_id: u32) -> anyhow::Result<()> {
+        let mut packet = Packet::new(self.id, 0);
+        packet.add_frame(AckFrame::new(packet_id).into());
+        packet.add_frame(self.window_frame());
+        self.transmit(packet).await
+    }
+
+    async fn announce_window(&mut self) -> anyhow::Result<()> {
+        let mut packet = Packet::new(self.id, 0);
+        packet.add_frame(self.window_frame());
+        self.transmit(packet).await
+    }
+
+    fn window_frame(&mut self) -> Frame {
+        self.announced = self.free();
+        FlowControlFrame::new(self.announced as u32).into()
+    }
+
+    async fn transmit(&mut self, packet: Packet) -> anyhow::Result<()> {
+        debug!("Sending packet {:?}", packet);
+        self.output
+            .send(packet)
+            .await
+            .context("Failed to send packet")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::{DataFrame, ExitFrame};
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_connection() {
+        let (client_tx, server_rx) = channel(64);
+        let (server_tx, client_rx) = channel(64);
+        let server = tokio::spawn(async move {
+            let mut server = Connection::accept(server_rx, server_tx, 7, Timeouts::default())
+                .await
+                .unwrap();
+            let mut received = Vec::new();
+            while let Some(frame) = server.recv().await {
+                received.push(frame);
+            }
+            server.close().await.unwrap();
+            received
+        });
+
+        let client = Connection::connect(client_rx, client_tx).await.unwrap();
+        assert_eq!(client.id(), 7);
+        let mut sender = client.sender();
+        for i in 0..100u64 {
+            let data = DataFrame::new(1, i, Bytes::from(vec![i as u8; 100]));
+            sender.send(data.into()).await.unwrap();
+        }
+        sender.send(ExitFrame::new().into()).await.unwrap();
+        drop(sender);
+        client.close().await.unwrap();
+
+        //stream frames arrive in order, the flow control and the Exit are handled by the connection
+        let received = server.await.unwrap();
+        assert_eq!(received.len(), 100);
+        for (i, frame) in received.into_iter().enumerate() {
+            match frame {
+                Frame::Data(data) => assert_eq!(data.offset(), i as u64),
+                _ => panic!(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_receiver() {
+        let timeouts = Timeouts {
+            ack: Duration::from_millis(100),
+            max_retries: 2,
+        };
+        let (client_tx, server_rx) = channel(64);
+        let (server_tx, client_rx) = channel(64);
+        let server = tokio::spawn(async move {
+            let mut server = Connection::accept(server_rx, server_tx, 7, timeouts)
+                .await
+                .unwrap();
+            //the window closes while nothing is taken, for many ACK timeouts
+            tokio::time::sleep(Duration::from_millis(1000)).await;
+            let mut offsets = Vec::new();
+            while let Some(frame) = server.recv().await {
+                offsets.push(frame.data().unwrap().offset());
+            }
+            server.close().await.unwrap();
+            offsets
+        });
+
+        let client = Connection::connect_with(client_rx, client_tx, timeouts)
+            .await
+            .unwrap();
+        let mut sender = client.sender();
+        let frames = 4 * RECEIVE_WINDOW as u64 / 1000;
+        for i in 0..frames {
+            let data = DataFrame::new(1, i, Bytes::from(vec![i as u8; 1000]));
+            sender.send(data.into()).await.unwrap();
+        }
+        sender.send(ExitFrame::new().into()).await.unwrap();
+        drop(sender);
+        client.close().await.unwrap();
+        assert_eq!(server.await.unwrap(), (0..frames).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,332 @@
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// Kind of access a command needs on the path it refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Directory exported under a name, addressed by clients as `name/path`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Share {
+    pub name: String,
+    pub path: PathBuf,
+    pub writable: bool,
+}
+
+impl Share {
+    pub fn new(name: &str, path: &Path, writable: bool) -> Self {
+        Share {
+            name: name.into(),
+            path: path.into(),
+            writable,
+        }
+    }
+}
+
+impl FromStr for Share {
+    type Err = anyhow::Error;
+
+    /// Parse a share from `name:path[:ro|rw]`, shares are read-only by default.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, rest) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected name:path[:ro|rw], got {}", s))?;
+        let (path, writable) = match rest.rsplit_once(':') {
+            Some((path, "ro")) => (path, false),
+            Some((path, "rw")) => (path, true),
+            _ => (rest, false),
+        };
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(anyhow!("Invalid share name: {:?}", name));
+        }
+        if path.is_empty() {
+            return Err(anyhow!("Missing path for share {}", name));
+        }
+        Ok(Share::new(name, Path::new(path), writable))
+    }
+}
+
+/// Directory trees the stream handlers are allowed to operate on.
+///
+/// Without a root or shares every path is used as is, which is what the
+/// client wants for its local files. With a root, command paths are
+/// interpreted relative to it and anything escaping it is rejected. Named
+/// shares take precedence over the root for paths starting with their name.
+#[derive(Clone, Debug, Default)]
+pub struct Export {
+    root: Option<PathBuf>,
+    shares: HashMap<String, Share>,
+}
+
+impl Export {
+    pub fn new(root: &Path) -> io::Result<Self> {
+        Ok(Export {
+            root: Some(canonical_dir(root)?),
+            shares: HashMap::new(),
+        })
+    }
+
+    pub fn unrestricted() -> Self {
+        Export::default()
+    }
+
+    pub fn add_share(&mut self, share: Share) -> io::Result<()> {
+        if self.shares.contains_key(&share.name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Share {} defined twice", share.name),
+            ));
+        }
+        let path = canonical_dir(&share.path)?;
+        self.shares
+            .insert(share.name.clone(), Share { path, ..share });
+        Ok(())
+    }
+
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    pub fn shares(&self) -> impl Iterator<Item = &Share> {
+        self.shares.values()
+    }
+
+    /// Share a command path refers to, if any.
+    pub fn share_for(&self, path: &Path) -> Option<&Share> {
+        let name = path.components().find_map(|c| match c {
+            Component::CurDir => None,
+            c => Some(c.as_os_str()),
+        })?;
+        self.shares.get(name.to_str()?)
+    }
+
+    pub fn is_unrestricted(&self) -> bool {
+        self.root.is_none() && self.shares.is_empty()
+    }
+
+    /// Map a path received in a command to the local path it refers to.
+    pub fn resolve(&self, path: &Path, access: Access) -> io::Result<PathBuf> {
+        if self.is_unrestricted() {
+            return Ok(path.to_path_buf());
+        }
+
+        let mut components = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(c) => components.push(c),
+                Component::CurDir => {}
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                    return Err(permission_denied(path));
+                }
+            }
+        }
+
+        //the first component selects the share, if there is one by that name
+        let share = self.share_for(path);
+        let (base, writable, rest) = match (share, &self.root) {
+            (Some(share), _) => (&share.path, share.writable, &components[1..]),
+            (None, Some(root)) => (root, true, &components[..]),
+            (None, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No such share: {}", path.display()),
+                ))
+            }
+        };
+
+        if access == Access::Write && !writable {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Permission denied: {} is read-only", path.display()),
+            ));
+        }
+
+        let mut resolved = base.clone();
+        resolved.extend(rest);
+        confine(base, resolved).map_err(|e| match e.kind() {
+            io::ErrorKind::PermissionDenied => permission_denied(path),
+            _ => e,
+        })
+    }
+}
+
+fn canonical_dir(path: &Path) -> io::Result<PathBuf> {
+    let path = path.canonicalize()?;
+    if !path.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Export root {} is not a directory", path.display()),
+        ));
+    }
+    Ok(path)
+}
+
+/// Follow symlinks in `path` and make sure the result stays below `base`.
+fn confine(base: &Path, path: PathBuf) -> io::Result<PathBuf> {
+    //find the longest existing prefix, the rest may be created later on
+    let mut existing = path;
+    let mut missing = Vec::new();
+    loop {
+        match existing.symlink_metadata() {
+            Ok(_) => break,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                match existing.file_name() {
+                    Some(name) => missing.push(name.to_os_string()),
+                    None => return Err(e),
+                }
+                existing.pop();
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    //dangling symlinks are rejected since we can't tell where they lead
+    let mut canonical = existing
+        .canonicalize()
+        .map_err(|_| io::Error::from(io::ErrorKind::PermissionDenied))?;
+    if !canonical.starts_with(base) {
+        return Err(io::ErrorKind::PermissionDenied.into());
+    }
+    canonical.extend(missing.iter().rev());
+    Ok(canonical)
+}
+
+fn permission_denied(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("Permission denied: {}", path.display()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn setup(name: &str) -> (PathBuf, Export) {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("root/sub")).unwrap();
+        fs::write(dir.join("root/sub/file.txt"), "inside").unwrap();
+        fs::write(dir.join("secret.txt"), "outside").unwrap();
+        let export = Export::new(&dir.join("root")).unwrap();
+        (dir, export)
+    }
+
+    #[test]
+    fn test_unrestricted_passthrough() {
+        let export = Export::unrestricted();
+        let path = Path::new("/etc/../etc/hostname");
+        assert_eq!(export.resolve(path, Access::Write).unwrap(), path);
+    }
+
+    #[test]
+    fn test_resolve_inside_root() {
+        let (dir, export) = setup("rft_export_inside");
+        let root = dir.join("root").canonicalize().unwrap();
+        assert_eq!(
+            export
+                .resolve(Path::new("sub/file.txt"), Access::Read)
+                .unwrap(),
+            root.join("sub/file.txt")
+        );
+        assert_eq!(
+            export
+                .resolve(Path::new("./sub/new/file.txt"), Access::Write)
+                .unwrap(),
+            root.join("sub/new/file.txt")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reject_traversal() {
+        let (dir, export) = setup("rft_export_traversal");
+        for path in ["../secret.txt", "sub/../../secret.txt", "/etc/shadow"] {
+            let err = export.resolve(Path::new(path), Access::Read).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_reject_escaping_symlink() {
+        let (dir, export) = setup("rft_export_symlink");
+        std::os::unix::fs::symlink(dir.join("secret.txt"), dir.join("root/link")).unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("root/dirlink")).unwrap();
+        std::os::unix::fs::symlink(dir.join("nowhere"), dir.join("root/dangling")).unwrap();
+        for path in ["link", "dirlink/secret.txt", "dirlink/new.txt", "dangling"] {
+            let err = export.resolve(Path::new(path), Access::Read).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_share() {
+        assert_eq!(
+            "logs:/var/log/app:ro".parse::<Share>().unwrap(),
+            Share::new("logs", Path::new("/var/log/app"), false)
+        );
+        assert_eq!(
+            "drop:/srv/incoming:rw".parse::<Share>().unwrap(),
+            Share::new("drop", Path::new("/srv/incoming"), true)
+        );
+        assert_eq!(
+            "data:/srv/data".parse::<Share>().unwrap(),
+            Share::new("data", Path::new("/srv/data"), false)
+        );
+        assert!("nopath".parse::<Share>().is_err());
+        assert!("..:/srv/data".parse::<Share>().is_err());
+    }
+
+    #[test]
+    fn test_resolve_shares() {
+        let (dir, mut export) = setup("rft_export_shares");
+        fs::create_dir_all(dir.join("ro")).unwrap();
+        fs::create_dir_all(dir.join("rw")).unwrap();
+        export
+            .add_share(Share::new("logs", &dir.join("ro"), false))
+            .unwrap();
+        export
+            .add_share(Share::new("drop", &dir.join("rw"), true))
+            .unwrap();
+        let dir = dir.canonicalize().unwrap();
+
+        assert_eq!(
+            export
+                .resolve(Path::new("logs/app.log"), Access::Read)
+                .unwrap(),
+            dir.join("ro/app.log")
+        );
+        let err = export
+            .resolve(Path::new("logs/app.log"), Access::Write)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            export
+                .resolve(Path::new("drop/new.bin"), Access::Write)
+                .unwrap(),
+            dir.join("rw/new.bin")
+        );
+        //unknown names fall back to the root
+        assert_eq!(
+            export
+                .resolve(Path::new("sub/file.txt"), Access::Read)
+                .unwrap(),
+            dir.join("root/sub/file.txt")
+        );
+        let err = export
+            .resolve(Path::new("logs/../../secret.txt"), Access::Read)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
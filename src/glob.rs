@@ -0,0 +1,287 @@
+use anyhow::anyhow;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Char(char),
+    //`?`, any character but `/`
+    One,
+    //`*`, any number of characters but `/`
+    Any,
+    //`**`, any number of characters including `/`
+    AnyPath,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+/// Shell style pattern matched against relative paths.
+///
+/// Supports `?`, `*`, `**` and character classes like `[a-z]` or `[!0-9]`,
+/// a backslash makes the character after it match only itself.
+///
+/// A pattern without a `/` matches the file name at any depth, otherwise it
+/// has to match the whole path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+    source: String,
+    tokens: Vec<Token>,
+}
+
+impl Pattern {
+    pub fn matches(&self, path: &Path) -> bool {
+        match self.source.contains('/') {
+            true => self.matches_path(path),
+            false => path
+                .file_name()
+                .is_some_and(|name| self.matches_path(Path::new(name))),
+        }
+    }
+
+    /// Whether the pattern matches the whole path, even without a `/` in it.
+    pub fn matches_path(&self, path: &Path) -> bool {
+        let chars: Vec<char> = path.to_string_lossy().chars().collect();
+        match_tokens(&self.tokens, &chars)
+    }
+}
+
+/// Whether `s` contains glob syntax that isn't escaped with a backslash.
+pub fn is_pattern(s: &str) -> bool {
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Escape `s` so that it is no pattern and matches only itself.
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The path `s` escapes, see [`escape`].
+pub fn unescape(s: &str) -> String {
+    let mut chars = s.chars();
+    let mut unescaped = String::with_capacity(s.len());
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.push(chars.next().unwrap_or(c)),
+            c => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Split a path into its leading directories without glob syntax and the
+/// pattern for the rest, e.g. `data/2026-10-*/*.parquet` into `data` and
+/// `2026-10-*/*.parquet`. Escapes are removed from the directories.
+pub fn split_base(path: &Path) -> (PathBuf, PathBuf) {
+    let mut components = path.components();
+    let mut base = PathBuf::new();
+    for component in components.by_ref() {
+        let name = component.as_os_str().to_string_lossy();
+        if is_pattern(&name) {
+            let mut rest = PathBuf::from(component.as_os_str());
+            rest.extend(components);
+            return (base, rest);
+        }
+        base.push(unescape(&name));
+    }
+    (base, PathBuf::new())
+}
+
+impl FromStr for Pattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Vec::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '\\' => Token::Char(chars.next().unwrap_or(c)),
+                '?' => Token::One,
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    Token::AnyPath
+                }
+                '*' => Token::Any,
+                '[' => {
+                    let negated = chars.next_if(|&c| c == '!' || c == '^').is_some();
+                    let mut ranges = Vec::new();
+                    loop {
+                        match chars.next() {
+                            Some(']') if !ranges.is_empty() => break,
+                            Some(start) => {
+                                let start = match start {
+                                    '\\' => chars.next().unwrap_or(start),
+                                    c => c,
+                                };
+                                match chars.next_if_eq(&'-') {
+                                    Some(_) => match chars.next() {
+                                        Some('\\') if chars.peek().is_some() => {
+                                            ranges.push((start, chars.next().unwrap_or('\\')))
+                                        }
+                                        Some(end) if end != ']' => ranges.push((start, end)),
+                                        _ => return Err(anyhow!("Unterminated range in {}", s)),
+                                    },
+                                    None => ranges.push((start, start)),
+                                }
+                            }
+                            None => return Err(anyhow!("Unterminated character class in {}", s)),
+                        }
+                    }
+                    Token::Class { negated, ranges }
+                }
+                c => Token::Char(c),
+            };
+            tokens.push(token);
+        }
+        Ok(Pattern {
+            source: s.into(),
+            tokens,
+        })
+    }
+}
+
+fn match_tokens(tokens: &[Token], s: &[char]) -> bool {
+    let Some(token) = tokens.first() else {
+        return s.is_empty();
+    };
+    let rest = &tokens[1..];
+    match token {
+        //`**/` also matches no directory at all
+        Token::AnyPath => {
+            (rest.first() == Some(&Token::Char('/')) && match_tokens(&rest[1..], s))
+                || (0..=s.len()).any(|i| match_tokens(rest, &s[i..]))
+        }
+        Token::Any => (0..=s.len())
+            .take_while(|&i| i == 0 || s[i - 1] != '/')
+            .any(|i| match_tokens(rest, &s[i..])),
+        Token::One => s.first().is_some_and(|&c| c != '/') && match_tokens(rest, &s[1..]),
+        Token::Class { negated, ranges } => {
+            s.first().is_some_and(|&c| {
+                c != '/' && ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated
+            }) && match_tokens(rest, &s[1..])
+        }
+        Token::Char(c) => s.first() == Some(c) && match_tokens(rest, &s[1..]),
+    }
+}
+
+/// Include and exclude patterns selecting the entries of a directory tree.
+///
+/// Excluding a directory excludes everything below it, include patterns
+/// only select files. Without include patterns every file is selected.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+}
+
+impl Filter {
+    pub fn new(include: Vec<Pattern>, exclude: Vec<Pattern>) -> Self {
+        Filter { include, exclude }
+    }
+
+    /// Whether the entry at `path`, relative to the root of the tree, is selected.
+    pub fn selects(&self, path: &Path, is_dir: bool) -> bool {
+        let excluded = path
+            .ancestors()
+            .filter(|p| !p.as_os_str().is_empty())
+            .any(|p| self.exclude.iter().any(|x| x.matches(p)));
+        !excluded
+            && (is_dir || self.include.is_empty() || self.include.iter().any(|i| i.matches(path)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        pattern.parse::<Pattern>().unwrap().matches(Path::new(path))
+    }
+
+    #[test]
+    fn test_pattern() {
+        assert!(matches("*.parquet", "data/2026-10-01/a.parquet"));
+        assert!(!matches("*.parquet", "data/a.parquet.tmp"));
+        assert!(matches(
+            "data/2026-10-*/*.parquet",
+            "data/2026-10-01/a.parquet"
+        ));
+        assert!(!matches("data/*.parquet", "data/2026-10-01/a.parquet"));
+        assert!(matches("data/**/*.parquet", "data/2026-10-01/a.parquet"));
+        assert!(matches("data/**/*.parquet", "data/a.parquet"));
+        assert!(matches("**", "any/thing"));
+        assert!(matches("log.?", "log.1"));
+        assert!(!matches("log.?", "log.12"));
+        assert!(matches("log.[0-9]", "log.7"));
+        assert!(!matches("log.[!0-9]", "log.7"));
+        assert!(matches("[ab]c", "bc"));
+        assert!("log.[0-9".parse::<Pattern>().is_err());
+        //escaped characters only match themselves
+        assert!(matches("report\\[1\\].csv", "report[1].csv"));
+        assert!(!matches("report\\[1\\].csv", "report1.csv"));
+        assert!(matches("what\\?.txt", "what?.txt"));
+        assert!(!matches("what\\?.txt", "whatx.txt"));
+        assert!(matches("[\\]a]*", "]x"));
+        assert!("[]".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn test_split_base() {
+        let split = |path| split_base(Path::new(path));
+        assert_eq!(
+            split("data/2026-10-*/*.parquet"),
+            ("data".into(), "2026-10-*/*.parquet".into())
+        );
+        assert_eq!(split("/var/log/*.log"), ("/var/log".into(), "*.log".into()));
+        assert_eq!(split("*.csv"), ("".into(), "*.csv".into()));
+        assert_eq!(split("data/a.csv"), ("data/a.csv".into(), "".into()));
+        assert!(is_pattern("log.[0-9]"));
+        assert!(!is_pattern("data/a.csv"));
+        assert!(!is_pattern("data/report\\[1\\].csv"));
+        assert!(is_pattern("data\\[1\\]/*.csv"));
+        assert_eq!(
+            split(r"data\[1\]/*.csv"),
+            ("data[1]".into(), "*.csv".into())
+        );
+        let name = r"a[1]\b*?.csv";
+        assert!(!is_pattern(&escape(name)));
+        assert_eq!(unescape(&escape(name)), name);
+        assert!(matches(&escape(name), name));
+        let pattern: Pattern = "*.csv".parse().unwrap();
+        assert!(pattern.matches(Path::new("a/b.csv")));
+        assert!(!pattern.matches_path(Path::new("a/b.csv")));
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter::new(
+            vec!["*.rs".parse().unwrap()],
+            vec!["target".parse().unwrap(), "*.bak.rs".parse().unwrap()],
+        );
+        assert!(filter.selects(Path::new("src/main.rs"), false));
+        assert!(!filter.selects(Path::new("src/main.bak.rs"), false));
+        assert!(!filter.selects(Path::new("README.md"), false));
+        assert!(filter.selects(Path::new("src"), true));
+        assert!(!filter.selects(Path::new("target"), true));
+        assert!(!filter.selects(Path::new("target/debug/build.rs"), false));
+        assert!(Filter::default().selects(Path::new("anything"), false));
+    }
+}
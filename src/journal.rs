@@ -0,0 +1,151 @@
+use anyhow::anyhow;
+use data_encoding::HEXLOWER;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Progress of a download, kept next to it as `<file>.rft-partial`.
+///
+/// The checksum is the SHA-256 of the remote file when the download started,
+/// a continued download only picks up at the offset if it is still the same.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Journal {
+    pub remote: PathBuf,
+    /// End of the data received in order from the server.
+    pub offset: u64,
+    pub checksum: Vec<u8>,
+    pub host: Ipv4Addr,
+    pub port: u16,
+}
+
+impl Journal {
+    const SUFFIX: &'static str = ".rft-partial";
+
+    /// Path of the journal of the download stored at `local`.
+    pub fn path(local: &Path) -> PathBuf {
+        let mut path = local.as_os_str().to_owned();
+        path.push(Self::SUFFIX);
+        path.into()
+    }
+
+    pub fn is_journal(path: &Path) -> bool {
+        path.as_os_str().to_string_lossy().ends_with(Self::SUFFIX)
+    }
+
+    /// Journal of the download stored at `local`, None if there is none.
+    pub fn load(local: &Path) -> anyhow::Result<Option<Self>> {
+        match fs::read_to_string(Self::path(local)) {
+            Ok(s) => Ok(Some(s.parse()?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, local: &Path) -> io::Result<()> {
+        fs::write(Self::path(local), self.to_string())
+    }
+
+    pub fn remove(local: &Path) -> io::Result<()> {
+        match fs::remove_file(Self::path(local)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the journal was written for `remote` on the server at `host:port`.
+    pub fn matches(&self, remote: &Path, host: Ipv4Addr, port: u16) -> bool {
+        self.remote == remote && self.host == host && self.port == port
+    }
+}
+
+impl fmt::Display for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "remote={}", self.remote.display())?;
+        writeln!(f, "offset={}", self.offset)?;
+        writeln!(f, "checksum={}", HEXLOWER.encode(&self.checksum))?;
+        writeln!(f, "host={}", self.host)?;
+        writeln!(f, "port={}", self.port)
+    }
+}
+
+impl FromStr for Journal {
+    type Err = anyhow::Error;
+
+    /// Parse the `key=value` lines written by Display.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut remote = None;
+        let mut offset = None;
+        let mut checksum = None;
+        let mut host = None;
+        let mut port = None;
+        for line in s.lines().filter(|l| !l.is_empty()) {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected key=value, got {}", line))?;
+            match key {
+                "remote" => remote = Some(PathBuf::from(value)),
+                "offset" => offset = Some(value.parse()?),
+                "checksum" => checksum = Some(HEXLOWER.decode(value.as_bytes())?),
+                "host" => host = Some(value.parse()?),
+                "port" => port = Some(value.parse()?),
+                _ => return Err(anyhow!("Unknown journal entry: {}", key)),
+            }
+        }
+        let missing = |key| anyhow!("Journal entry {} is missing", key);
+        Ok(Journal {
+            remote: remote.ok_or_else(|| missing("remote"))?,
+            offset: offset.ok_or_else(|| missing("offset"))?,
+            checksum: checksum.ok_or_else(|| missing("checksum"))?,
+            host: host.ok_or_else(|| missing("host"))?,
+            port: port.ok_or_else(|| missing("port"))?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_roundtrip() {
+        let journal = Journal {
+            remote: PathBuf::from("logs/app.log"),
+            offset: 123456,
+            checksum: vec![0xab; 32],
+            host: Ipv4Addr::new(10, 0, 0, 1),
+            port: 8080,
+        };
+        assert_eq!(journal.to_string().parse::<Journal>().unwrap(), journal);
+        assert!(journal.matches(Path::new("logs/app.log"), Ipv4Addr::new(10, 0, 0, 1), 8080));
+        assert!(!journal.matches(Path::new("logs/app.log"), Ipv4Addr::new(10, 0, 0, 1), 8081));
+
+        assert!("remote=a\noffset=1\n".parse::<Journal>().is_err());
+        assert!("garbage".parse::<Journal>().is_err());
+    }
+
+    #[test]
+    fn test_journal_file() {
+        let local = std::env::temp_dir().join("rft_journal_file.bin");
+        assert_eq!(
+            Journal::path(&local),
+            local.with_extension("bin.rft-partial")
+        );
+
+        Journal::remove(&local).unwrap();
+        assert_eq!(Journal::load(&local).unwrap(), None);
+        let journal = Journal {
+            remote: PathBuf::from("big.bin"),
+            offset: 42,
+            checksum: vec![1, 2, 3],
+            host: Ipv4Addr::LOCALHOST,
+            port: 9000,
+        };
+        journal.save(&local).unwrap();
+        assert_eq!(Journal::load(&local).unwrap(), Some(journal));
+        Journal::remove(&local).unwrap();
+        assert!(!Journal::path(&local).exists());
+    }
+}
@@ -1,6 +1,18 @@
+pub mod acl;
 pub mod client;
+pub mod congestion;
 pub mod conn_handler;
+pub mod connection;
+pub mod export;
+pub mod glob;
+pub mod journal;
 pub mod loss_simulation;
+pub mod migration;
+pub mod quota;
 pub mod server;
+pub mod session;
+pub mod storage;
 pub mod stream_handler;
+pub mod stream_queue;
+pub mod sync;
 pub mod wire;
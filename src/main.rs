@@ -1,26 +1,28 @@
 use std::net::Ipv4Addr;
 use std::path::PathBuf;
 use std::process::exit;
+use std::time::Duration;
 use tokio::runtime;
 
-use clap::Parser;
-use log::{error, info};
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use log::{error, info, warn};
 
-mod client;
-mod conn_handler;
-mod loss_simulation;
-mod server;
-mod stream_handler;
-#[allow(dead_code)]
-mod wire;
-
-use client::Client;
-use loss_simulation::LossSimulation;
-use server::Server;
+use rft::acl::AclRule;
+use rft::client::{split_spec, Client, ClientConfig, GlobTransfer, Transfer, TreeTransfer};
+use rft::export::Share;
+use rft::glob::{self, Filter, Pattern};
+use rft::loss_simulation::LossSimulation;
+use rft::quota::{parse_share_quota, parse_size};
+use rft::server::ServerBuilder;
+use rft::stream_handler::Durability;
+use rft::sync::{SyncDirection, SyncTransfer};
 
 #[derive(Debug, Parser)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(
         short,
         long,
@@ -42,6 +44,113 @@ struct Cli {
     )]
     port: u16,
 
+    #[arg(
+        long,
+        help = "Directory to serve files from in server mode, paths outside of it are rejected.",
+        requires = "server"
+    )]
+    root: Option<PathBuf>,
+
+    #[arg(
+        long = "share",
+        value_name = "NAME:PATH[:ro|rw]",
+        help = "Export a directory under a share name in server mode, can be given multiple times.",
+        requires = "server"
+    )]
+    shares: Vec<Share>,
+
+    #[arg(
+        long = "acl",
+        value_name = "CLIENT:PREFIX:OPS",
+        help = "Allow a client IP ([addr] for IPv6, or * for all) the comma separated operations (read, write, list, stat, checksum, delete or all) below a path prefix in server mode, can be given multiple times. Without rules everything is allowed.",
+        requires = "server"
+    )]
+    acl: Vec<AclRule>,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        help = "Largest file clients may upload in server mode, e.g. 512M.",
+        requires = "server"
+    )]
+    max_file_size: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        help = "Bytes each client may upload while the server is running, e.g. 10G.",
+        requires = "server"
+    )]
+    client_quota: Option<u64>,
+
+    #[arg(
+        long = "share-quota",
+        value_name = "NAME:SIZE",
+        value_parser = parse_share_quota,
+        help = "Bytes that may be stored in a share in server mode, can be given multiple times.",
+        requires = "server"
+    )]
+    share_quotas: Vec<(String, u64)>,
+
+    #[arg(
+        long,
+        help = "Upload into hidden temporary files that replace the target only once complete, in server mode.",
+        requires = "server"
+    )]
+    atomic_writes: bool,
+
+    #[arg(
+        long,
+        value_name = "POLICY",
+        default_value = "on-complete",
+        help = "When uploads are synced to disk in server mode: none, on-complete or periodic[:SECONDS].",
+        requires = "server"
+    )]
+    durability: Durability,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "10",
+        help = "Time open connections get to finish on SIGINT or SIGTERM in server mode, before the server closes them.",
+        requires = "server"
+    )]
+    grace_period: u64,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "300",
+        help = "Close connections nothing was received on for this long in server mode, 0 keeps them open.",
+        requires = "server"
+    )]
+    idle_timeout: u64,
+
+    #[arg(
+        long,
+        help = "Keep the mode bits and modification times of transferred files.",
+        conflicts_with = "server"
+    )]
+    preserve: bool,
+
+    #[arg(
+        long = "continue",
+        action,
+        help = "Continue interrupted downloads at the offsets recorded in their .rft-partial journals.",
+        conflicts_with = "server"
+    )]
+    continue_downloads: bool,
+
+    #[arg(
+        long,
+        action,
+        help = "Compare the SHA-256 of every download with the server's, corrupt files are downloaded once more.",
+        conflicts_with = "server"
+    )]
+    verify: bool,
+
     #[arg(
         short,
         help = "Markov probability that packet lost after non-lost packet."
@@ -52,10 +161,107 @@ struct Cli {
     q: Option<f64>,
 
     #[arg(
-        help = "Files to download from the server",
+        long,
+        value_name = "DIR",
+        help = "Directory to store downloaded files in, under their file name unless a local path is given.",
+        conflicts_with = "server"
+    )]
+    output_dir: Option<PathBuf>,
+
+    #[arg(
+        value_name = "REMOTE[:LOCAL]",
+        help = "Files or globs to download from the server, optionally to a different local path. Colons in REMOTE are escaped as \\:",
         required_unless_present = "server"
     )]
-    files: Option<Vec<PathBuf>>,
+    files: Option<Vec<String>>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Download a file, or a directory with -r, from the server.
+    Get {
+        #[arg(help = "IP address of the server")]
+        host: Ipv4Addr,
+
+        #[arg(help = "File, directory or glob to download")]
+        remote: PathBuf,
+
+        #[arg(help = "Local path to store it at")]
+        local: PathBuf,
+
+        #[command(flatten)]
+        tree: TreeArgs,
+    },
+    /// Upload a file, or a directory with -r, to the server.
+    Put {
+        #[arg(help = "IP address of the server")]
+        host: Ipv4Addr,
+
+        #[arg(help = "File or directory to upload")]
+        local: PathBuf,
+
+        #[arg(help = "Path to write it to on the server")]
+        remote: PathBuf,
+
+        #[command(flatten)]
+        tree: TreeArgs,
+    },
+    /// Transfer the missing and changed files of a directory tree, from
+    /// HOST:REMOTE to LOCAL or the other way around.
+    Sync {
+        #[arg(value_name = "SOURCE", help = "HOST:REMOTE or a local directory")]
+        source: String,
+
+        #[arg(value_name = "DESTINATION", help = "HOST:REMOTE or a local directory")]
+        destination: String,
+
+        #[arg(
+            long,
+            help = "Delete files and directories the source doesn't have, only when syncing from the server."
+        )]
+        delete: bool,
+
+        #[arg(long, help = "Only print what would be transferred and deleted.")]
+        dry_run: bool,
+    },
+}
+
+// the server and path of a sync endpoint in HOST:REMOTE form, the remote
+// path may contain further colons, local paths only if they don't start
+// with an address and a colon, e.g. with ./
+fn parse_remote(endpoint: &str) -> Option<(Ipv4Addr, PathBuf)> {
+    let (host, path) = endpoint.split_once(':')?;
+    Some((host.parse().ok()?, PathBuf::from(path)))
+}
+
+#[derive(Debug, Args)]
+struct TreeArgs {
+    #[arg(short, long, help = "Transfer a directory with all files below it.")]
+    recursive: bool,
+
+    #[arg(
+        long,
+        value_name = "GLOB",
+        help = "Only transfer files matching the pattern, can be given multiple times.",
+        requires = "recursive"
+    )]
+    include: Vec<Pattern>,
+
+    #[arg(
+        long,
+        value_name = "GLOB",
+        help = "Skip files and directories matching the pattern, can be given multiple times.",
+        requires = "recursive"
+    )]
+    exclude: Vec<Pattern>,
+}
+
+impl TreeArgs {
+    // the tree to transfer, or None for a single file
+    fn tree(self, local: PathBuf, remote: PathBuf) -> Option<TreeTransfer> {
+        self.recursive
+            .then(|| TreeTransfer::new(local, remote, Filter::new(self.include, self.exclude)))
+    }
 }
 
 // TODOs:
@@ -67,9 +273,65 @@ struct Cli {
 // - reduce boilerplate in wire module with macros
 // - consider splitting wire module into multiple modules
 
+/// SIGINT and SIGTERM, or Ctrl-C where there are no such signals.
+struct Signals {
+    #[cfg(unix)]
+    interrupt: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    //must be created within the runtime
+    fn new() -> anyhow::Result<Self> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            Ok(Signals {
+                interrupt: signal(SignalKind::interrupt())?,
+                terminate: signal(SignalKind::terminate())?,
+            })
+        }
+        #[cfg(not(unix))]
+        Ok(Signals {})
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = self.interrupt.recv() => {}
+            _ = self.terminate.recv() => {}
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+fn print_banner() {
+    let banner = "                                      
+ ███████████   ███████████ ███████████
+░░███░░░░░███ ░░███░░░░░░█░█░░░███░░░█
+ ░███    ░███  ░███   █ ░ ░   ░███  ░ 
+ ░██████████   ░███████       ░███    
+ ░███░░░░░███  ░███░░░█       ░███    
+ ░███    ░███  ░███  ░        ░███    
+ █████   █████ █████          █████   
+░░░░░   ░░░░░ ░░░░░          ░░░░░    
+                                      ";
+    println!("{}", banner);
+}
+
 fn main() {
     env_logger::init();
     let args = Cli::parse();
+    if args.server && args.command.is_some() {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "commands can't be used in server mode",
+            )
+            .exit();
+    }
 
     let loss_sim = LossSimulation::from_options(args.p, args.q);
 
@@ -91,18 +353,145 @@ fn main() {
     let result = runtime.block_on(async move {
         if args.server {
             info!("Running in server mode");
-            Server::new(args.port, loss_sim).run().await
+            print_banner();
+            let mut server = ServerBuilder::new()
+                .port(args.port)
+                .atomic_writes(args.atomic_writes)
+                .durability(args.durability)
+                .grace_period(Duration::from_secs(args.grace_period))
+                .idle_timeout(match args.idle_timeout {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                });
+            if let Some(root) = args.root {
+                server = server.root(root);
+            }
+            for share in args.shares {
+                server = server.share(share);
+            }
+            for rule in args.acl {
+                server = server.acl_rule(rule);
+            }
+            if let Some(size) = args.max_file_size {
+                server = server.max_file_size(size);
+            }
+            if let Some(size) = args.client_quota {
+                server = server.client_quota(size);
+            }
+            for (name, size) in args.share_quotas {
+                server = server.share_quota(&name, size);
+            }
+            if let Some(loss_sim) = loss_sim {
+                server = server.loss_simulation(loss_sim);
+            }
+            let mut signals = Signals::new()?;
+            let mut server = server.spawn().await?;
+            tokio::select! {
+                result = server.wait() => return result,
+                _ = signals.recv() => {}
+            }
+            info!("Received signal, send it again to exit immediately");
+            server.stop();
+            tokio::select! {
+                result = server.wait() => result,
+                _ = signals.recv() => {
+                    warn!("Exiting without waiting for open connections");
+                    exit(1)
+                }
+            }
+        } else if let Some(Command::Get {
+            host,
+            remote,
+            local,
+            tree,
+        }) = args.command
+        {
+            info!("Running in client mode");
+            let mut config = ClientConfig::new(host, args.port, Vec::new(), loss_sim);
+            config.preserve = args.preserve;
+            config.continue_downloads = args.continue_downloads;
+            config.verify = args.verify;
+            if glob::is_pattern(&remote.to_string_lossy()) {
+                if tree.recursive {
+                    return Err(anyhow::anyhow!("Globs can't be downloaded recursively"));
+                }
+                config.download_globs.push(GlobTransfer::new(local, remote));
+            } else {
+                let remote = PathBuf::from(glob::unescape(&remote.to_string_lossy()));
+                match tree.tree(local.clone(), remote.clone()) {
+                    Some(tree) => config.download_dirs.push(tree),
+                    None => config.downloads.push(Transfer::new(local, remote)),
+                }
+            }
+            Client::new(config).start().await
+        } else if let Some(Command::Put {
+            host,
+            local,
+            remote,
+            tree,
+        }) = args.command
+        {
+            info!("Running in client mode");
+            let mut config = ClientConfig::new(host, args.port, Vec::new(), loss_sim);
+            config.preserve = args.preserve;
+            match tree.tree(local.clone(), remote.clone()) {
+                Some(tree) => config.upload_dirs.push(tree),
+                None => config.uploads.push(Transfer::new(local, remote)),
+            }
+            Client::new(config).start().await
+        } else if let Some(Command::Sync {
+            source,
+            destination,
+            delete,
+            dry_run,
+        }) = args.command
+        {
+            info!("Running in client mode");
+            let ((host, remote), local, direction) =
+                match (parse_remote(&source), parse_remote(&destination)) {
+                    (Some(remote), None) => (remote, destination, SyncDirection::Download),
+                    (None, Some(remote)) => (remote, source, SyncDirection::Upload),
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "Exactly one of source and destination must be HOST:REMOTE"
+                        ))
+                    }
+                };
+            let mut config = ClientConfig::new(host, args.port, Vec::new(), loss_sim);
+            // Unchanged files are recognized by their modification time next time
+            config.preserve = true;
+            config.dry_run = dry_run;
+            config
+                .syncs
+                .push(SyncTransfer::new(local.into(), remote, direction, delete));
+            Client::new(config).start().await
         } else {
             info!("Running in client mode");
-            let config = client::ClientConfig::new(
+            let mut config = ClientConfig::new(
                 args.host
                     .ok_or_else(|| anyhow::anyhow!("Host is required for client mode"))?,
                 args.port,
-                args.files
-                    .ok_or_else(|| anyhow::anyhow!("Files are required for client mode"))?,
+                Vec::new(),
                 loss_sim,
             );
-            if config.files.is_empty() {
+            let files = args
+                .files
+                .ok_or_else(|| anyhow::anyhow!("Files are required for client mode"))?;
+            for spec in files {
+                let (remote, _) = split_spec(&spec)?;
+                match glob::is_pattern(&remote.to_string_lossy()) {
+                    true => config
+                        .download_globs
+                        .push(GlobTransfer::download(&spec, args.output_dir.as_deref())?),
+                    false => config
+                        .downloads
+                        .push(Transfer::download(&spec, args.output_dir.as_deref())?),
+                }
+            }
+            config.preserve = args.preserve;
+            config.continue_downloads = args.continue_downloads;
+            config.verify = args.verify;
+            if config.downloads.is_empty() && config.download_globs.is_empty() {
                 return Err(anyhow::anyhow!("No files specified"));
             }
             Client::new(config).start().await
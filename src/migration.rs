@@ -0,0 +1,170 @@
+use crate::wire::{Frame, Packet, PathChallengeFrame, PathResponseFrame};
+use log::debug;
+use rand::Rng;
+use std::net::SocketAddr;
+
+/// Where a packet came from, see [`PeerAddress::on_receive`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Origin {
+    /// The address of the peer.
+    Current,
+    /// The peer proved to be at this new address, the connection moved there.
+    Migrated,
+    /// An address the peer isn't known to be at, e.g. a forged one.
+    Unvalidated,
+}
+
+/// Address of the peer of a connection, following the peer when it migrates.
+///
+/// A packet from another address doesn't move the connection right away, or
+/// anyone could redirect it by sending one with a forged source. The address
+/// becomes a candidate instead, which is sent a random nonce in a
+/// PathChallengeFrame, see [`PeerAddress::challenge`]. Only once the nonce
+/// comes back from the candidate, the peer proved it receives packets there
+/// and the connection moves over. Until then the packets of the candidate
+/// are kept from the connection and its packets still go to the old address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerAddress {
+    addr: SocketAddr,
+    candidate: Option<Candidate>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Candidate {
+    addr: SocketAddr,
+    nonce: u64,
+}
+
+impl PeerAddress {
+    pub fn new(addr: SocketAddr) -> Self {
+        PeerAddress {
+            addr,
+            candidate: None,
+        }
+    }
+
+    /// The validated address of the peer, which all packets are sent to.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The challenge to send to the address the peer may have moved to.
+    ///
+    /// It is the same until the candidate is replaced, so it can be sent
+    /// again for every packet from there until it was answered.
+    pub fn challenge(&self) -> Option<(SocketAddr, PathChallengeFrame)> {
+        self.candidate
+            .map(|candidate| (candidate.addr, PathChallengeFrame::new(candidate.nonce)))
+    }
+
+    /// Note that `packet` was received from `from`.
+    pub fn on_receive(&mut self, packet: &Packet, from: SocketAddr) -> Origin {
+        if from == self.addr {
+            return Origin::Current;
+        }
+        match self.candidate {
+            Some(candidate) if candidate.addr == from => {
+                let answered = packet
+                    .frames
+                    .iter()
+                    .filter_map(Frame::path_response)
+                    .any(|response| response.nonce() == candidate.nonce);
+                if !answered {
+                    return Origin::Unvalidated;
+                }
+                self.addr = from;
+                self.candidate = None;
+                Origin::Migrated
+            }
+            //a newer candidate replaces the last one, e.g. after repeated rebinding
+            _ => {
+                debug!("Received packet from {}, validating address", from);
+                self.candidate = Some(Candidate {
+                    addr: from,
+                    nonce: rand::thread_rng().gen(),
+                });
+                Origin::Unvalidated
+            }
+        }
+    }
+}
+
+/// Whether `packet` validates addresses, it isn't passed on to the connection.
+pub fn is_probe(packet: &Packet) -> bool {
+    packet
+        .frames
+        .iter()
+        .any(|frame| frame.is_path_challenge() || frame.is_path_response())
+}
+
+/// The answer to the challenges in `packet`, to send back where it came from.
+pub fn respond(packet: &Packet) -> Option<Packet> {
+    let mut response = Packet::new(packet.connection_id(), 0);
+    for challenge in packet.frames.iter().filter_map(Frame::path_challenge) {
+        response.add_frame(PathResponseFrame::new(challenge.nonce()).into());
+    }
+    (!response.frames.is_empty()).then_some(response)
+}
+
+/// An unsequenced packet of connection `connection_id` carrying `challenge`.
+pub fn probe(connection_id: u32, challenge: PathChallengeFrame) -> Packet {
+    let mut packet = Packet::new(connection_id, 0);
+    packet.add_frame(challenge.into());
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::AckFrame;
+
+    fn ack(packet_id: u32) -> Packet {
+        let mut packet = Packet::new(5, 0);
+        packet.add_frame(AckFrame::new(packet_id).into());
+        packet
+    }
+
+    #[test]
+    fn test_peer_address() {
+        let old: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let new: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let forged: SocketAddr = "10.0.0.3:6000".parse().unwrap();
+        let mut peer = PeerAddress::new(old);
+        assert_eq!(peer.on_receive(&ack(1), old), Origin::Current);
+        assert!(peer.challenge().is_none());
+
+        //no ACK proves anything, only the nonce sent there does
+        assert_eq!(peer.on_receive(&ack(u32::MAX), new), Origin::Unvalidated);
+        assert_eq!(peer.addr(), old);
+        let (to, challenge) = peer.challenge().unwrap();
+        assert_eq!(to, new);
+        assert_eq!(peer.on_receive(&ack(u32::MAX), new), Origin::Unvalidated);
+        let wrong = respond(&probe(5, PathChallengeFrame::new(!challenge.nonce()))).unwrap();
+        assert_eq!(peer.on_receive(&wrong, new), Origin::Unvalidated);
+        assert_eq!(peer.challenge().unwrap().1.nonce(), challenge.nonce());
+
+        //the nonce comes back from the candidate only
+        let response = respond(&probe(5, challenge.clone())).unwrap();
+        assert!(is_probe(&response) && respond(&response).is_none());
+        assert_eq!(peer.on_receive(&response, old), Origin::Current);
+        assert_eq!(peer.on_receive(&response, new), Origin::Migrated);
+        assert_eq!(peer.addr(), new);
+        assert!(peer.challenge().is_none());
+
+        //a forged address gets a new challenge it can't answer
+        assert_eq!(peer.on_receive(&ack(9), forged), Origin::Unvalidated);
+        let (to, challenge) = peer.challenge().unwrap();
+        assert_eq!(to, forged);
+        assert_eq!(peer.on_receive(&response, forged), Origin::Unvalidated);
+        assert_eq!(peer.on_receive(&ack(9), new), Origin::Current);
+        assert_eq!(peer.addr(), new);
+        assert!(!is_probe(&ack(9)));
+        assert_eq!(
+            respond(&probe(5, challenge.clone())).unwrap().frames[0]
+                .path_response()
+                .unwrap()
+                .nonce(),
+            challenge.nonce()
+        );
+    }
+}
@@ -0,0 +1,295 @@
+use crate::export::Share;
+use crate::storage::Storage;
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Limits applied to uploads on the server.
+///
+/// Client quotas count the bytes a client has written since the server
+/// started, share quotas the bytes currently stored in the share. A share is
+/// scanned once when it is first written to, its usage is tracked from then on.
+#[derive(Debug, Default)]
+pub struct Quotas {
+    pub max_file_size: Option<u64>,
+    pub client_quota: Option<u64>,
+    pub share_quotas: HashMap<String, u64>,
+    usage: Mutex<Usage>,
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    //only kept while a client quota is configured
+    clients: HashMap<IpAddr, u64>,
+    shares: HashMap<String, u64>,
+    //reserved by running uploads but not written yet, the disk doesn't show it
+    pending: u64,
+}
+
+impl Quotas {
+    pub fn new(
+        max_file_size: Option<u64>,
+        client_quota: Option<u64>,
+        share_quotas: HashMap<String, u64>,
+    ) -> Self {
+        Quotas {
+            max_file_size,
+            client_quota,
+            share_quotas,
+            usage: Mutex::default(),
+        }
+    }
+
+    /// Reserve `length` bytes to be written to `path` starting at `offset`.
+    ///
+    /// None if that exceeds a limit or the free space of `storage`, taking
+    /// the space reserved by other uploads into account.
+    pub fn reserve<'a>(
+        &'a self,
+        storage: &'a dyn Storage,
+        client: Option<IpAddr>,
+        share: Option<&Share>,
+        path: &Path,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<Option<Reservation<'a>>> {
+        let share = share.filter(|share| self.share_quotas.contains_key(&share.name));
+        if let Some(share) = share {
+            if !self.usage.lock().unwrap().shares.contains_key(&share.name) {
+                //scan without holding the lock, the first upload to finish it wins
+                let stored = storage.usage(&share.path)?;
+                let mut usage = self.usage.lock().unwrap();
+                usage.shares.entry(share.name.clone()).or_insert(stored);
+            }
+        }
+
+        let mut reservation = Reservation {
+            quotas: self,
+            client: client.filter(|_| self.client_quota.is_some()),
+            share: share.map(|share| share.name.clone()),
+            storage,
+            path: path.to_path_buf(),
+            end: offset,
+            written: offset,
+        };
+        Ok(reservation.extend(length)?.then_some(reservation))
+    }
+
+    pub fn usage(&self, client: IpAddr) -> u64 {
+        let usage = self.usage.lock().unwrap();
+        *usage.clients.get(&client).unwrap_or(&0)
+    }
+}
+
+/// Space reserved for an upload by [`Quotas::reserve`].
+///
+/// Dropping it releases what wasn't written, written bytes stay charged.
+pub struct Reservation<'a> {
+    quotas: &'a Quotas,
+    client: Option<IpAddr>,
+    share: Option<String>,
+    storage: &'a dyn Storage,
+    path: PathBuf,
+    //offsets up to which the upload is reserved and was written
+    end: u64,
+    written: u64,
+}
+
+impl Reservation<'_> {
+    /// Account `bytes` about to be written, false if they exceed a limit.
+    ///
+    /// Writing more than was reserved checks the limits again, the announced
+    /// length may have been wrong or missing.
+    pub fn write(&mut self, bytes: u64) -> io::Result<bool> {
+        let missing = (self.written + bytes).saturating_sub(self.end);
+        if missing > 0 && !self.extend(missing)? {
+            return Ok(false);
+        }
+        self.written += bytes;
+        self.quotas.usage.lock().unwrap().pending -= bytes;
+        Ok(true)
+    }
+
+    /// Note that `bytes` stored in the share were replaced or truncated.
+    pub fn release_stored(&self, bytes: u64) {
+        if let Some(share) = &self.share {
+            let mut usage = self.quotas.usage.lock().unwrap();
+            if let Some(stored) = usage.shares.get_mut(share) {
+                *stored = stored.saturating_sub(bytes);
+            }
+        }
+    }
+
+    fn extend(&mut self, bytes: u64) -> io::Result<bool> {
+        let quotas = self.quotas;
+        if quotas
+            .max_file_size
+            .is_some_and(|max| self.end + bytes > max)
+        {
+            return Ok(false);
+        }
+        let space = self.storage.available_space(&self.path)?;
+
+        let mut usage = quotas.usage.lock().unwrap();
+        if space.is_some_and(|space| usage.pending + bytes > space) {
+            return Ok(false);
+        }
+        if let (Some(quota), Some(client)) = (quotas.client_quota, self.client) {
+            if usage.clients.get(&client).unwrap_or(&0) + bytes > quota {
+                return Ok(false);
+            }
+        }
+        if let Some(share) = &self.share {
+            if usage.shares[share] + bytes > quotas.share_quotas[share] {
+                return Ok(false);
+            }
+        }
+
+        if let Some(client) = self.client {
+            *usage.clients.entry(client).or_insert(0) += bytes;
+        }
+        if let Some(share) = &self.share {
+            *usage.shares.get_mut(share).unwrap() += bytes;
+        }
+        usage.pending += bytes;
+        self.end += bytes;
+        Ok(true)
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let unused = self.end - self.written;
+        let mut usage = self.quotas.usage.lock().unwrap();
+        if let Some(client) = self.client {
+            if let Some(used) = usage.clients.get_mut(&client) {
+                *used -= unused;
+                if *used == 0 {
+                    usage.clients.remove(&client);
+                }
+            }
+        }
+        if let Some(share) = &self.share {
+            if let Some(stored) = usage.shares.get_mut(share) {
+                *stored = stored.saturating_sub(unused);
+            }
+        }
+        usage.pending -= unused;
+    }
+}
+
+/// Parse a byte count with an optional K, M, G or T suffix (powers of 1024).
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let (digits, shift) = match s.chars().last() {
+        Some('K' | 'k') => (&s[..s.len() - 1], 10),
+        Some('M' | 'm') => (&s[..s.len() - 1], 20),
+        Some('G' | 'g') => (&s[..s.len() - 1], 30),
+        Some('T' | 't') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|e| anyhow!("Invalid size {:?}: {}", s, e))?;
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("Size {:?} too large", s))
+}
+
+/// Parse a share quota from `name:size`.
+pub fn parse_share_quota(s: &str) -> anyhow::Result<(String, u64)> {
+    let (name, size) = s
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Expected name:size, got {}", s))?;
+    Ok((name.into(), parse_size(size)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{LocalStorage, MemoryStorage};
+    use std::fs;
+    use std::net::Ipv4Addr;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1234").unwrap(), 1234);
+        assert_eq!(parse_size("4K").unwrap(), 4096);
+        assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("12X").is_err());
+        assert!(parse_size("99999999999T").is_err());
+        assert_eq!(
+            parse_share_quota("drop:1M").unwrap(),
+            ("drop".to_string(), 1 << 20)
+        );
+    }
+
+    #[test]
+    fn test_reserve() {
+        let dir = std::env::temp_dir().join("rft_quota_reserve");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.bin"), [0u8; 100]).unwrap();
+        fs::write(dir.join("sub/b.bin"), [0u8; 50]).unwrap();
+
+        let share = Share::new("drop", &dir, true);
+        let quotas = Quotas::new(
+            Some(1000),
+            Some(500),
+            HashMap::from([("drop".to_string(), 400)]),
+        );
+        let path = dir.join("new.bin");
+        let reserve = |client, share, offset, length| {
+            quotas
+                .reserve(&LocalStorage, client, share, &path, offset, length)
+                .unwrap()
+        };
+
+        //share quota minus the 150 bytes already stored
+        assert!(reserve(Some(CLIENT), Some(&share), 0, 251).is_none());
+        let first = reserve(Some(CLIENT), Some(&share), 0, 200).unwrap();
+        //concurrent uploads can't both pass the check before either is written
+        assert!(reserve(None, Some(&share), 0, 51).is_none());
+        assert!(reserve(None, Some(&share), 0, 50).is_some());
+        //max file size minus the offset
+        assert!(reserve(None, None, 900, 101).is_none());
+        assert!(reserve(None, None, 900, 100).is_some());
+        //client quota minus what was reserved
+        assert!(reserve(Some(CLIENT), None, 0, 301).is_none());
+        assert_eq!(quotas.usage(CLIENT), 200);
+
+        //a failed upload releases what it didn't write
+        let mut first = first;
+        assert!(first.write(120).unwrap());
+        drop(first);
+        assert_eq!(quotas.usage(CLIENT), 120);
+        assert!(reserve(None, Some(&share), 0, 130).is_some());
+        assert!(reserve(None, Some(&share), 0, 131).is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_write() {
+        let storage = MemoryStorage::new();
+        let quotas = Quotas::new(None, Some(100), HashMap::new());
+        let mut reservation = quotas
+            .reserve(&storage, Some(CLIENT), None, Path::new("a.bin"), 0, 50)
+            .unwrap()
+            .unwrap();
+        assert!(reservation.write(40).unwrap());
+        //writing beyond the reservation extends it within the quota
+        assert!(reservation.write(60).unwrap());
+        assert!(!reservation.write(1).unwrap());
+        drop(reservation);
+        assert_eq!(quotas.usage(CLIENT), 100);
+        assert!(quotas
+            .reserve(&storage, None, None, Path::new("a.bin"), 0, 1 << 40)
+            .unwrap()
+            .is_some());
+    }
+}
@@ -1,179 +1,941 @@
+use crate::acl::{Acl, AclRule};
 use crate::conn_handler::connection_handler;
+use crate::connection::{proposed_id, Timeouts};
+use crate::export::{Export, Share};
 use crate::loss_simulation::LossSimulation;
+use crate::migration::{self, Origin, PeerAddress};
+use crate::quota::Quotas;
+use crate::storage::{LocalStorage, Storage};
+use crate::stream_handler::{Durability, HandlerContext};
 use crate::wire::{Assemble, Packet};
-use futures::channel::mpsc;
-use futures::{SinkExt, StreamExt};
+use anyhow::anyhow;
+use futures::channel::{mpsc, oneshot};
+use futures::future::{select, Shared};
+use futures::{future, FutureExt, SinkExt, StreamExt};
 use log::{debug, error, info, warn};
+use rand::Rng;
 use std::collections::HashMap;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::sync::{Arc, Mutex};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::UdpSocket;
-use tokio::task::spawn_blocking;
-use tokio::time::timeout;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep, Instant};
 
-pub struct Server {
-    port: u16,
+//packets a connection may have waiting before further ones are dropped
+const INPUT_BUFFER: usize = 128;
+//how often connections are checked for having been idle too long
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Configuration of a server, started with [`ServerBuilder::spawn`].
+///
+/// By default it listens on a port chosen by the system on all interfaces and
+/// serves any path the clients ask for.
+pub struct ServerBuilder {
+    addr: SocketAddr,
+    root: Option<PathBuf>,
+    shares: Vec<Share>,
+    acl: Vec<AclRule>,
+    max_file_size: Option<u64>,
+    client_quota: Option<u64>,
+    share_quotas: HashMap<String, u64>,
+    atomic_writes: bool,
+    durability: Durability,
+    timeouts: Timeouts,
+    max_connections: Option<usize>,
+    idle_timeout: Option<Duration>,
+    grace_period: Duration,
     loss_sim: Option<LossSimulation>,
+    storage: Arc<dyn Storage>,
 }
 
-#[allow(dead_code)]
-#[allow(unused_mut)]
-#[allow(unused_variables)]
-impl Server {
-    pub fn new(port: u16, loss_sim: Option<LossSimulation>) -> Self {
-        Server { port, loss_sim }
-    }
-
-    pub async fn run(&self) -> anyhow::Result<()> {
-        self::Server::print_banner();
-        info!("Server running on port {}", self.port);
-        //HashMap for client IPs
-        //let mut output_map: HashMap<u32, SocketAddr> = HashMap::new();
-        let output_map: Arc<Mutex<HashMap<u32, SocketAddr>>> = Arc::new(Mutex::new(HashMap::new()));
-        let mut loss_sim = self
-            .loss_sim
-            .clone()
-            .map(|loss_sim| Arc::new(Mutex::new(loss_sim)));
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder {
+            addr: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into(),
+            root: None,
+            shares: Vec::new(),
+            acl: Vec::new(),
+            max_file_size: None,
+            client_quota: None,
+            share_quotas: HashMap::new(),
+            atomic_writes: false,
+            durability: Durability::default(),
+            timeouts: Timeouts::default(),
+            max_connections: None,
+            idle_timeout: Some(Duration::from_secs(300)),
+            grace_period: Duration::from_secs(10),
+            loss_sim: None,
+            storage: Arc::new(LocalStorage),
+        }
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Address to listen on, port 0 lets the system choose one.
+    pub fn bind(mut self, addr: impl Into<SocketAddr>) -> Self {
+        self.addr = addr.into();
+        self
+    }
+
+    /// Listen on `port` on all interfaces.
+    pub fn port(self, port: u16) -> Self {
+        self.bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))
+    }
 
-        //HashMap for connection handlers
-        let mut input_map: HashMap<u32, mpsc::Sender<Packet>> = HashMap::new();
+    /// Confine the server to the directory `root`.
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = Some(root.into());
+        self
+    }
+
+    pub fn share(mut self, share: Share) -> Self {
+        self.shares.push(share);
+        self
+    }
 
-        //mpsc channel <Packet>: handler output -> transmitter input
-        let (mux_tx, mut mux_rx) = mpsc::channel(32);
+    pub fn acl_rule(mut self, rule: AclRule) -> Self {
+        self.acl.push(rule);
+        self
+    }
+
+    pub fn max_file_size(mut self, size: u64) -> Self {
+        self.max_file_size = Some(size);
+        self
+    }
 
-        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), self.port))
+    /// Bytes each client may upload while the server runs.
+    pub fn client_quota(mut self, size: u64) -> Self {
+        self.client_quota = Some(size);
+        self
+    }
+
+    /// Bytes that may be stored in the share `name`.
+    pub fn share_quota(mut self, name: &str, size: u64) -> Self {
+        self.share_quotas.insert(name.to_string(), size);
+        self
+    }
+
+    pub fn atomic_writes(mut self, atomic_writes: bool) -> Self {
+        self.atomic_writes = atomic_writes;
+        self
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// How long connections wait for unresponsive clients.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Connections open at the same time, further connection requests are ignored.
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Time after which the server closes connections it received nothing on,
+    /// 5 minutes by default. None keeps them open until the client leaves.
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Time open connections get to finish once the server shuts down, before
+    /// the server closes them.
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Where files are read from and written to, the local file system by
+    /// default. Roots and shares only make sense for the local file system.
+    pub fn storage(mut self, storage: impl Storage + 'static) -> Self {
+        self.storage = Arc::new(storage);
+        self
+    }
+
+    pub fn loss_simulation(mut self, loss_sim: LossSimulation) -> Self {
+        self.loss_sim = Some(loss_sim);
+        self
+    }
+
+    /// Bind the socket and serve clients in the background until the returned
+    /// handle shuts the server down.
+    pub async fn spawn(self) -> anyhow::Result<ServerHandle> {
+        let mut export = match &self.root {
+            Some(root) => {
+                Export::new(root).map_err(|e| anyhow!("Invalid root {:?}: {}", root, e))?
+            }
+            None => Export::unrestricted(),
+        };
+        for share in self.shares {
+            let name = share.name.clone();
+            export
+                .add_share(share)
+                .map_err(|e| anyhow!("Invalid share {}: {}", name, e))?;
+        }
+        let context = HandlerContext {
+            export: Arc::new(export),
+            acl: Arc::new(Acl::new(self.acl)),
+            quotas: Arc::new(Quotas::new(
+                self.max_file_size,
+                self.client_quota,
+                self.share_quotas,
+            )),
+            client: None,
+            atomic_writes: self.atomic_writes,
+            durability: self.durability,
+            storage: self.storage,
+        };
+
+        let socket = UdpSocket::bind(self.addr)
             .await
-            .expect("Failed to bind socket");
-        let udp_rx = Arc::new(socket);
-        let udp_tx = udp_rx.clone();
+            .map_err(|e| anyhow!("Failed to bind socket to {}: {}", self.addr, e))?;
+        let local_addr = socket.local_addr()?;
+        info!("Server listening on {}", local_addr);
+        if let Some(root) = context.export.root() {
+            info!("Serving files from {}", root.display());
+        }
+        for share in context.export.shares() {
+            info!(
+                "Serving share {} from {} ({})",
+                share.name,
+                share.path.display(),
+                if share.writable { "rw" } else { "ro" }
+            );
+        }
+        if !context.acl.is_empty() {
+            info!("Access control list enabled");
+        }
+        if context.atomic_writes {
+            info!("Atomic uploads enabled");
+        }
+        info!("Durability policy: {:?}", context.durability);
 
-        //TODO: delete closed connections from HashMaps
+        let stats = Arc::new(Counters::default());
+        let (shutdown, signal) = oneshot::channel();
+        let (output, outgoing) = mpsc::channel(32);
+        let (ended, finished) = mpsc::unbounded();
+        let (exit, exit_signal) = oneshot::channel();
+        let dispatcher = Dispatcher {
+            socket,
+            context,
+            timeouts: self.timeouts,
+            max_connections: self.max_connections,
+            idle_timeout: self.idle_timeout,
+            grace_period: self.grace_period,
+            loss_sim: self.loss_sim,
+            stats: stats.clone(),
+            connections: HashMap::new(),
+            ids: HashMap::new(),
+            output,
+            ended,
+            exit: Some(exit),
+            exit_signal: exit_signal.shared(),
+        };
+        let task = tokio::spawn(dispatcher.run(signal, outgoing, finished));
+        Ok(ServerHandle {
+            local_addr,
+            stats,
+            shutdown: Some(shutdown),
+            task: Some(task),
+        })
+    }
+}
 
-        //start packet switching task
-        let mut output_map_switch = output_map.clone();
-        let mut loss_sim_switch = loss_sim.clone();
-        tokio::spawn(async move {
-            let mut buf = [0; 2048];
-            let mut cid_ctr = 1u32;
-            loop {
-                let (size, client_addr) = udp_rx
-                    .recv_from(&mut buf)
-                    .await
-                    .expect("UDP Socket rx error");
-                let packet = spawn_blocking(move || Packet::parse_buf(&buf[..size]))
-                    .await
-                    .unwrap()
-                    .expect("Failed to parse packet");
-                if let Some(loss_sim) = loss_sim_switch.as_mut() {
-                    if loss_sim.lock().unwrap().drop_packet() {
-                        warn!(
-                            "Simulated loss of received packet {} occurred!",
-                            packet.packet_id()
-                        );
-                        continue;
-                    }
-                }
+/// Counters of a running server, see [`ServerHandle::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Connections accepted since the server started.
+    pub connections: u64,
+    /// Connections that haven't ended yet.
+    pub open_connections: u64,
+    pub packets_received: u64,
+    pub packets_sent: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    connections: AtomicU64,
+    open_connections: AtomicU64,
+    packets_received: AtomicU64,
+    packets_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+/// A server running in the background.
+///
+/// Dropping the handle shuts the server down like [`ServerHandle::shutdown`],
+/// without waiting for it.
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    stats: Arc<Counters>,
+    shutdown: Option<oneshot::Sender<()>>,
+    //None once it stopped
+    task: Option<JoinHandle<anyhow::Result<()>>>,
+}
 
-                debug!("Received packet: {:?}", &packet);
+impl ServerHandle {
+    /// Address the server listens on, with the port the system chose.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
 
-                match packet.connection_id() {
-                    0 => {
-                        debug!("New connection, ID: {}", cid_ctr);
-                        let (mut ctx, crx) = mpsc::channel(128);
+    pub fn stats(&self) -> ServerStats {
+        let stats = &self.stats;
+        ServerStats {
+            connections: stats.connections.load(Ordering::Relaxed),
+            open_connections: stats.open_connections.load(Ordering::Relaxed),
+            packets_received: stats.packets_received.load(Ordering::Relaxed),
+            packets_sent: stats.packets_sent.load(Ordering::Relaxed),
+            bytes_received: stats.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: stats.bytes_sent.load(Ordering::Relaxed),
+        }
+    }
 
-                        ctx.send(packet).await.unwrap();
+    /// Stop accepting connections and wait for the open ones to end.
+    ///
+    /// Connections still open after the grace period are closed by the server.
+    pub async fn shutdown(mut self) -> anyhow::Result<()> {
+        self.stop();
+        self.wait().await
+    }
 
-                        input_map.insert(cid_ctr, ctx);
-                        {
-                            let mut omap_mtx = output_map_switch.lock().unwrap();
-                            omap_mtx.insert(cid_ctr, client_addr);
-                        }
+    /// Start shutting down like [`ServerHandle::shutdown`] without waiting.
+    pub fn stop(&mut self) {
+        //the dropped sender is the signal
+        self.shutdown.take();
+    }
 
-                        let mux_tx_c = mux_tx.clone();
-                        tokio::spawn(async move {
-                            connection_handler(crx, mux_tx_c, cid_ctr)
-                                .await
-                                .expect("connection handler error");
-                        });
+    /// Wait for the server to stop, which it only does once shut down.
+    pub async fn wait(&mut self) -> anyhow::Result<()> {
+        let Some(task) = self.task.as_mut() else {
+            return Ok(());
+        };
+        let result = task.await;
+        self.task = None;
+        result.map_err(|e| anyhow!("Server task panicked: {}", e))?
+    }
+}
 
-                        cid_ctr += 1;
-                    }
-                    _ => {
-                        match input_map.get_mut(&packet.connection_id()) {
-                            None => {
-                                warn!(
-                                    "Discard Packet for unknown connection with packet_id {}",
-                                    packet.packet_id()
-                                );
-                            }
-                            Some(s) => {
-                                let cid = packet.connection_id();
-                                match timeout(Duration::from_millis(1), s.send(packet)).await {
-                                    Ok(r) => match r {
-                                        Ok(_) => {}
-                                        Err(_) => {
-                                            error!("Packet for dead connection handler discarded!");
-                                            input_map.remove(&cid);
-                                            {
-                                                let mut omap_mtx =
-                                                    output_map_switch.lock().unwrap();
-                                                omap_mtx.remove(&cid);
-                                            }
-                                        }
-                                    },
-                                    Err(_) => {
-                                        //timeout: channel full -> drop packet
-                                        error!(
-                                            "connection handler input channel full, packet dropped"
-                                        );
-                                    }
-                                }
-                            }
-                        }
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+struct Peer {
+    addr: PeerAddress,
+    id: u32,
+    //id before the last change, until the client switched to the new one
+    retired: Option<u32>,
+    //id the client proposed, to recognize repeated connection requests
+    proposal: Option<u32>,
+    //first packet from an address the client may have moved to, passed on
+    //once it did so it needn't be sent again
+    held: Option<(SocketAddr, Packet)>,
+    input: mpsc::Sender<Packet>,
+    id_changes: mpsc::UnboundedSender<u32>,
+    last_seen: Instant,
+    //dropped to close the connection
+    close: Option<oneshot::Sender<()>>,
+}
+
+/// Hands received packets to the connections and sends theirs.
+struct Dispatcher {
+    socket: UdpSocket,
+    context: HandlerContext,
+    timeouts: Timeouts,
+    max_connections: Option<usize>,
+    idle_timeout: Option<Duration>,
+    grace_period: Duration,
+    loss_sim: Option<LossSimulation>,
+    stats: Arc<Counters>,
+    //by the id they were opened with, which stays the same when the id changes
+    connections: HashMap<u32, Peer>,
+    //connection ids in use and the connection they belong to
+    ids: HashMap<u32, u32>,
+    output: mpsc::Sender<(u32, Packet)>,
+    //ids of connections whose handler returned
+    ended: mpsc::UnboundedSender<u32>,
+    //dropped to close the connections still open after the grace period
+    exit: Option<oneshot::Sender<()>>,
+    exit_signal: Shared<oneshot::Receiver<()>>,
+}
+
+impl Dispatcher {
+    async fn run(
+        mut self,
+        mut shutdown: oneshot::Receiver<()>,
+        mut outgoing: mpsc::Receiver<(u32, Packet)>,
+        mut finished: mpsc::UnboundedReceiver<u32>,
+    ) -> anyhow::Result<()> {
+        let mut buf = [0; 2048];
+        let mut draining = false;
+        let grace = sleep(Duration::ZERO);
+        tokio::pin!(grace);
+        let mut sweep = interval(SWEEP_INTERVAL);
+
+        loop {
+            if draining && self.connections.is_empty() {
+                info!("Server on {} stopped", self.socket.local_addr()?);
+                return Ok(());
+            }
+            tokio::select! {
+                biased;
+                _ = &mut shutdown, if !draining => {
+                    info!(
+                        "Shutting down, waiting for {} open connections",
+                        self.connections.len()
+                    );
+                    draining = true;
+                    grace.as_mut().reset(Instant::now() + self.grace_period);
+                }
+                //packets are sent before the end of their connection is noticed
+                Some((key, packet)) = outgoing.next() => self.send(key, packet).await,
+                received = self.socket.recv_from(&mut buf) => match received {
+                    Ok((size, addr)) => self.dispatch(&buf[..size], addr, draining),
+                    //e.g. an ICMP port unreachable of a client that is gone
+                    Err(e) => warn!("Failed to receive packet: {}", e),
+                },
+                Some(key) = finished.next() => {
+                    //the handler may have been removed already after it stopped taking packets
+                    if let Some(peer) = self.remove(key) {
+                        info!(
+                            "Connection {} ended, {} open",
+                            peer.id,
+                            self.connections.len()
+                        );
                     }
                 }
+                _ = sweep.tick(), if self.idle_timeout.is_some() => self.close_idle(),
+                _ = &mut grace, if draining && self.exit.is_some() => {
+                    info!("Closing {} remaining connections", self.connections.len());
+                    self.exit = None;
+                }
+            }
+        }
+    }
+
+    fn dispatch(&mut self, buf: &[u8], addr: SocketAddr, draining: bool) {
+        self.stats.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .bytes_received
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        let packet = match Packet::parse_buf(buf) {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("Discarding malformed packet from {}: {}", addr, e);
+                return;
+            }
+        };
+        if let Some(loss_sim) = self.loss_sim.as_mut() {
+            if loss_sim.drop_packet() {
+                warn!(
+                    "Simulated loss of received packet {} occurred!",
+                    packet.packet_id()
+                );
+                return;
+            }
+        }
+        debug!("Received packet: {:?}", &packet);
+
+        let id = packet.connection_id();
+        if id == 0 {
+            self.accept(packet, addr, draining);
+            return;
+        }
+        let Some(&key) = self.ids.get(&id) else {
+            warn!(
+                "Discard Packet for unknown connection with packet_id {}",
+                packet.packet_id()
+            );
+            return;
+        };
+        //challenges are answered wherever they come from, the peer validates its address
+        if let Some(response) = migration::respond(&packet) {
+            self.send_probe(response, addr);
+        }
+        let peer = self
+            .connections
+            .get_mut(&key)
+            .expect("connection ids belong to open connections");
+        //the client switched to the new id, the old one is free again
+        if id == peer.id {
+            if let Some(retired) = peer.retired.take() {
+                self.ids.remove(&retired);
+            }
+        }
+        let origin = peer.addr.on_receive(&packet, addr);
+        let held = match origin {
+            Origin::Current => None,
+            Origin::Migrated => {
+                info!("Connection {} migrated to {}", peer.id, addr);
+                peer.held.take().filter(|(from, _)| *from == addr)
+            }
+            Origin::Unvalidated => {
+                debug!(
+                    "Rejecting packet for connection {} from unvalidated address {}",
+                    peer.id, addr
+                );
+                if !migration::is_probe(&packet)
+                    && peer.held.as_ref().is_none_or(|(from, _)| *from != addr)
+                {
+                    peer.held = Some((addr, packet));
+                }
+                //the challenge is repeated as long as packets come from there
+                if let Some((to, challenge)) = peer.addr.challenge() {
+                    let probe = migration::probe(peer.id, challenge);
+                    self.send_probe(probe, to);
+                }
+                return;
+            }
+        };
+        peer.last_seen = Instant::now();
+        let packets = held
+            .map(|(_, packet)| packet)
+            .into_iter()
+            .chain(Some(packet));
+        for packet in packets.filter(|packet| !migration::is_probe(packet)) {
+            if let Err(e) = peer.input.try_send(packet) {
+                if e.is_full() {
+                    error!("connection handler input channel full, packet dropped");
+                } else {
+                    error!("Packet for dead connection handler discarded!");
+                    self.remove(key);
+                    return;
+                }
+            }
+        }
+        //the connection can't be told by its id at the new address
+        if origin == Origin::Migrated {
+            self.change_id(key);
+        }
+    }
+
+    fn accept(&mut self, request: Packet, addr: SocketAddr, draining: bool) {
+        let proposal = proposed_id(&request);
+        if proposal.is_some()
+            && self
+                .connections
+                .values()
+                .any(|peer| peer.proposal == proposal && peer.addr.addr() == addr)
+        {
+            //the connection answers it by resending its first packet
+            debug!("Ignoring repeated connection request of {}", addr);
+            return;
+        }
+        if draining {
+            debug!(
+                "Ignoring connection request of {} while shutting down",
+                addr
+            );
+            return;
+        }
+        if self
+            .max_connections
+            .is_some_and(|max| self.connections.len() >= max)
+        {
+            warn!("Too many open connections, ignoring request of {}", addr);
+            return;
+        }
+        //the proposed id is used unless another connection has it
+        let id = match proposal {
+            Some(proposal) if !self.in_use(proposal) => proposal,
+            _ => self.allocate_id(),
+        };
+
+        let (mut input, packets) = mpsc::channel(INPUT_BUFFER);
+        input
+            .try_send(request)
+            .expect("new connection channel is empty");
+        let (close, closed) = oneshot::channel();
+        let (id_changes, changed_ids) = mpsc::unbounded();
+        let peer = Peer {
+            addr: PeerAddress::new(addr),
+            id,
+            retired: None,
+            proposal,
+            held: None,
+            input,
+            id_changes,
+            last_seen: Instant::now(),
+            close: Some(close),
+        };
+        self.connections.insert(id, peer);
+        self.ids.insert(id, id);
+        self.stats.connections.fetch_add(1, Ordering::Relaxed);
+        self.update_open();
+        info!(
+            "New connection {} from {}, {} open",
+            id,
+            addr,
+            self.connections.len()
+        );
+
+        //the packets are sent to wherever the connection is, whatever id they carry
+        let output = self
+            .output
+            .clone()
+            .with(move |packet| future::ready(Ok::<_, mpsc::SendError>((id, packet))));
+        let context = HandlerContext {
+            client: Some(addr.ip()),
+            ..self.context.clone()
+        };
+        let timeouts = self.timeouts;
+        let exit = select(self.exit_signal.clone(), closed).map(|_| ());
+        let ended = self.ended.clone();
+        tokio::spawn(async move {
+            let handler =
+                connection_handler(packets, output, id, context, timeouts, changed_ids, exit);
+            if let Err(e) = handler.await {
+                error!("Connection {} failed: {}", id, e);
             }
+            let _ = ended.unbounded_send(id);
         });
+    }
+
+    async fn send(&mut self, key: u32, packet: Packet) {
+        if let Some(loss_sim) = self.loss_sim.as_mut() {
+            if loss_sim.drop_packet() {
+                warn!(
+                    "Simulated loss of sent packet {} occurred!",
+                    packet.packet_id()
+                );
+                return;
+            }
+        }
+        //late packets of a closed connection have nowhere to go
+        let Some(peer) = self.connections.get_mut(&key) else {
+            warn!(
+                "Discarding packet for closed connection {}",
+                packet.connection_id()
+            );
+            return;
+        };
+        let addr = peer.addr.addr();
+        match self.socket.send_to(&packet.assemble(), addr).await {
+            Ok(size) => self.count_sent(size),
+            Err(e) => warn!("Failed to send packet to {}: {}", addr, e),
+        }
+    }
+
+    //probes aren't retransmitted, one that doesn't fit the socket buffer is lost
+    fn send_probe(&mut self, packet: Packet, addr: SocketAddr) {
+        if self.loss_sim.as_mut().is_some_and(|l| l.drop_packet()) {
+            warn!("Simulated loss of sent probe occurred!");
+            return;
+        }
+        match self.socket.try_send_to(&packet.assemble(), addr) {
+            Ok(size) => self.count_sent(size),
+            Err(e) => warn!("Failed to send probe to {}: {}", addr, e),
+        }
+    }
+
+    fn count_sent(&self, size: usize) {
+        self.stats.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .bytes_sent
+            .fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    //they are told so and end once the client confirmed it or stopped responding
+    fn close_idle(&mut self) {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return;
+        };
+        for peer in self.connections.values_mut() {
+            if peer.close.is_some() && peer.last_seen.elapsed() >= idle_timeout {
+                info!(
+                    "Closing connection {} of {}, idle for {:?}",
+                    peer.id,
+                    peer.addr.addr(),
+                    idle_timeout
+                );
+                peer.close = None;
+            }
+        }
+    }
+
+    /// Move the connection `key` to a new id, unless it is still moving.
+    fn change_id(&mut self, key: u32) {
+        if self
+            .connections
+            .get(&key)
+            .is_none_or(|peer| peer.retired.is_some())
+        {
+            return;
+        }
+        let new_id = self.allocate_id();
+        let peer = self.connections.get_mut(&key).unwrap();
+        if peer.id_changes.unbounded_send(new_id).is_ok() {
+            self.ids.insert(new_id, key);
+            peer.retired = Some(peer.id);
+            peer.id = new_id;
+        }
+    }
+
+    //ids of ended connections are used again, the keys of open ones never
+    fn in_use(&self, id: u32) -> bool {
+        self.ids.contains_key(&id) || self.connections.contains_key(&id)
+    }
 
-        //start packet sending
+    //random, so nobody can guess the ids of other clients and inject packets
+    fn allocate_id(&self) -> u32 {
+        let mut rng = rand::thread_rng();
         loop {
-            let packet = mux_rx.next().await.expect("server mux_rx closed");
-            if let Some(loss_sim) = loss_sim.as_mut() {
-                if loss_sim.lock().unwrap().drop_packet() {
-                    warn!(
-                        "Simulated loss of sent packet {} occurred!",
-                        packet.packet_id()
-                    );
-                    continue;
-                }
+            let id = rng.gen_range(1..=u32::MAX);
+            if !self.in_use(id) {
+                return id;
             }
-            let dest;
-            {
-                let omap_mtx = output_map.lock().unwrap();
-                dest = *omap_mtx
-                    .get(&packet.connection_id())
-                    .expect("connID not in output_map at tx");
+        }
+    }
+
+    fn remove(&mut self, key: u32) -> Option<Peer> {
+        let peer = self.connections.remove(&key)?;
+        self.ids.remove(&peer.id);
+        if let Some(retired) = peer.retired {
+            self.ids.remove(&retired);
+        }
+        self.update_open();
+        Some(peer)
+    }
+
+    fn update_open(&self) {
+        self.stats
+            .open_connections
+            .store(self.connections.len() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{transport, Client};
+    use crate::connection::Connection;
+    use crate::session::Session;
+    use crate::storage::MemoryStorage;
+    use crate::wire::{AckFrame, ConnIdChangeFrame, ExitFrame, Frame, PathResponseFrame};
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::AtomicBool;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_server_shutdown() {
+        let dir = "server_test";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        fs::write(Path::new(dir).join("a.txt"), b"hello").unwrap();
+        let server = ServerBuilder::new()
+            .bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .root(dir)
+            .grace_period(Duration::from_millis(100))
+            .spawn()
+            .await
+            .unwrap();
+        assert_ne!(server.local_addr().port(), 0);
+
+        let session = Client::connect(server.local_addr()).await.unwrap();
+        let entry = session.stat("a.txt").await.unwrap().unwrap();
+        assert_eq!(entry.size, 5);
+        let stats = server.stats();
+        assert_eq!(stats.connections, 1);
+        assert_eq!(stats.open_connections, 1);
+        assert!(stats.packets_received > 0 && stats.bytes_sent > 0);
+
+        //the session outlasts the grace period, so the server closes it
+        server.shutdown().await.unwrap();
+        assert!(session.stat("a.txt").await.is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_drain() {
+        let storage = MemoryStorage::new();
+        let mut server = ServerBuilder::new()
+            .bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .storage(storage.clone())
+            .spawn()
+            .await
+            .unwrap();
+        let session = Client::connect(server.local_addr()).await.unwrap();
+        let mut writer = session.open_write("upload.bin").await.unwrap();
+        writer.write_all(&[1; 3000]).await.unwrap();
+
+        //the transfer in progress may finish, then the server stops
+        server.stop();
+        writer.write_all(&[2; 3000]).await.unwrap();
+        writer.shutdown().await.unwrap();
+        session.close().await.unwrap();
+        server.wait().await.unwrap();
+        let data = storage.get("upload.bin").unwrap();
+        assert_eq!(data.len(), 6000);
+        assert_eq!(server.stats().open_connections, 0);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let server = ServerBuilder::new()
+            .bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .storage(MemoryStorage::new())
+            .idle_timeout(Some(Duration::from_millis(200)))
+            .spawn()
+            .await
+            .unwrap();
+        let session = Client::connect(server.local_addr()).await.unwrap();
+        assert_eq!(server.stats().open_connections, 1);
+
+        //the server closes the connection, the client confirms it
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while server.stats().open_connections > 0 {
+            assert!(Instant::now() < deadline, "connection wasn't closed");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(session.list("").await.is_err());
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migration() {
+        let storage = MemoryStorage::new();
+        storage.insert("a.bin", vec![7; 20000]).unwrap();
+        let server = ServerBuilder::new()
+            .bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .storage(storage)
+            .spawn()
+            .await
+            .unwrap();
+        let socket = || UdpSocket::bind("127.0.0.1:0");
+        let (input_a, mut output_a) = transport(socket().await.unwrap(), server.local_addr(), None);
+        let (input_b, mut output_b) = transport(socket().await.unwrap(), server.local_addr(), None);
+
+        //the client's packets come from the second socket once it rebound,
+        //what still arrives at the first one is counted and dropped
+        let rebound = Arc::new(AtomicBool::new(false));
+        let left_behind = Arc::new(AtomicU64::new(0));
+        let (output, mut outgoing) = mpsc::channel::<Packet>(32);
+        let sending = rebound.clone();
+        tokio::spawn(async move {
+            while let Some(packet) = outgoing.next().await {
+                let output = match sending.load(Ordering::Relaxed) {
+                    true => &mut output_b,
+                    false => &mut output_a,
+                };
+                let _ = output.send(packet).await;
+            }
+        });
+        let (receiving, counted) = (rebound.clone(), left_behind.clone());
+        let input_a = input_a.filter(move |_| {
+            let rebound = receiving.load(Ordering::Relaxed);
+            if rebound {
+                counted.fetch_add(1, Ordering::Relaxed);
             }
-            let packet_bytes = spawn_blocking(move || packet.assemble()).await?;
-            udp_tx
-                .send_to(&packet_bytes, dest)
-                .await
-                .expect("UDP Socket tx error");
-        }
-    }
-
-    fn print_banner() {
-        let banner = "                                      
- ███████████   ███████████ ███████████
-░░███░░░░░███ ░░███░░░░░░█░█░░░███░░░█
- ░███    ░███  ░███   █ ░ ░   ░███  ░ 
- ░██████████   ░███████       ░███    
- ░███░░░░░███  ░███░░░█       ░███    
- ░███    ░███  ░███  ░        ░███    
- █████   █████ █████          █████   
-░░░░░   ░░░░░ ░░░░░          ░░░░░    
-                                      ";
-        println!("{}", banner);
+            futures::future::ready(!rebound)
+        });
+        let input = futures::stream::select(input_a, input_b);
+        let session = Session::new(Connection::connect(input, output).await.unwrap());
+        let entry = session.stat("a.bin").await.unwrap().unwrap();
+        assert_eq!(entry.size, 20000);
+
+        //the server follows the client to the new address once it answered there
+        rebound.store(true, Ordering::Relaxed);
+        let mut data = Vec::new();
+        let mut reader = session.open_read("a.bin", ..).await.unwrap();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, vec![7; 20000]);
+        assert!(left_behind.load(Ordering::Relaxed) < 5);
+        session.close().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    //the id the request is answered under, and the change of it
+    async fn connect(
+        socket: &UdpSocket,
+        server: SocketAddr,
+        proposal: u32,
+    ) -> (u32, Option<(u32, u32)>) {
+        let mut request = Packet::new(0, 1);
+        request.add_frame(ConnIdChangeFrame::new(0, proposal).into());
+        socket.send_to(&request.assemble(), server).await.unwrap();
+        let mut buf = [0; 2048];
+        let size = socket.recv(&mut buf).await.unwrap();
+        let answer = Packet::parse_buf(&buf[..size]).unwrap();
+        let change = answer
+            .frames
+            .iter()
+            .find_map(Frame::conn_id_change)
+            .map(|change| (change.old_cid(), change.new_cid()));
+        (answer.connection_id(), change)
+    }
+
+    #[tokio::test]
+    async fn test_connection_id_negotiation() {
+        let server = ServerBuilder::new()
+            .bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .storage(MemoryStorage::new())
+            .spawn()
+            .await
+            .unwrap();
+        let addr = server.local_addr();
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        assert_eq!(connect(&first, addr, 5).await, (5, None));
+        //connections from the same port are told apart by their ids
+        assert_eq!(connect(&first, addr, 6).await, (6, None));
+
+        //a taken proposal is answered under a new id
+        let (id, change) = connect(&second, addr, 5).await;
+        assert!(id != 0 && id != 5 && id != 6);
+        assert_eq!(change, Some((5, id)));
+        assert_eq!(server.stats().open_connections, 3);
+    }
+
+    #[tokio::test]
+    async fn test_forged_address() {
+        let server = ServerBuilder::new()
+            .bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .storage(MemoryStorage::new())
+            .spawn()
+            .await
+            .unwrap();
+        let addr = server.local_addr();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let forger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        assert_eq!(connect(&client, addr, 5).await, (5, None));
+        let mut exit = Packet::new(5, 2);
+        exit.add_frame(ExitFrame::new().into());
+
+        //the connection doesn't see packets from another address, nor does
+        //acknowledging everything or guessing the nonce move it there
+        let mut forged = Packet::new(5, 0);
+        forged.add_frame(AckFrame::new(u32::MAX).into());
+        forged.add_frame(PathResponseFrame::new(rand::random()).into());
+        forger.send_to(&forged.assemble(), addr).await.unwrap();
+        let mut buf = [0; 2048];
+        let size = forger.recv(&mut buf).await.unwrap();
+        let challenge = Packet::parse_buf(&buf[..size]).unwrap();
+        assert_eq!(challenge.packet_id(), 0);
+        assert!(challenge.frames.iter().all(Frame::is_path_challenge));
+        forger.send_to(&exit.assemble(), addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(server.stats().open_connections, 1);
+        client.send_to(&exit.assemble(), addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(server.stats().open_connections, 0);
     }
 }
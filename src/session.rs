@@ -0,0 +1,721 @@
+use crate::client::{server_socket, transport, Client};
+use crate::connection::Connection;
+use crate::stream_queue::{deliver, StreamQueue};
+use crate::wire::*;
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::{ready, Sink, SinkExt, StreamExt};
+use log::{debug, warn};
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::future::poll_fn;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::ToSocketAddrs;
+use tokio::task::JoinHandle;
+
+//payload of the data frames a writer sends, like the server's reads
+const DATA_SIZE: usize = 1024;
+//frames of a stream handed to its reader at once, the rest waits in its queue
+const STREAM_BUFFER: usize = 16;
+
+impl Client {
+    /// Connect to the server at `addr` for use from other programs.
+    ///
+    /// Unlike [`Client::start`] nothing is written to disk, files are read
+    /// and written through the returned [`Session`].
+    pub async fn connect(addr: impl ToSocketAddrs) -> anyhow::Result<Session> {
+        let (socket, server) = server_socket(addr).await?;
+        let (input, output) = transport(socket, server, None);
+        Ok(Session::new(Connection::connect(input, output).await?))
+    }
+}
+
+/// An open connection to a server, for reading, writing and inspecting
+/// remote files.
+///
+/// Every operation gets a stream of its own, so they can run concurrently,
+/// e.g. from several tasks sharing the session.
+pub struct Session {
+    sender: Sender<Frame>,
+    streams: Arc<Mutex<Streams>>,
+    router: JoinHandle<anyhow::Result<()>>,
+}
+
+/// Receivers of the frames for the streams in use.
+struct Streams {
+    handlers: HashMap<u16, StreamQueue>,
+    //streams told to stop, their ids are only used again after their last frame
+    stopping: HashSet<u16>,
+    next_id: u16,
+    closed: bool,
+}
+
+/// A stream in use, freed again when dropped.
+struct OpenStream {
+    id: u16,
+    streams: Arc<Mutex<Streams>>,
+}
+
+impl OpenStream {
+    /// Tell the server to stop the stream, unless it already sent its last frame.
+    fn stop(&self, frames: &mut Receiver<Frame>, sender: &Sender<Frame>, ended: bool) {
+        //the router can't hand out the last frame anymore once the lock is released
+        let mut streams = self.streams.lock().unwrap();
+        frames.close();
+        let mut ended = ended;
+        while let Ok(Some(frame)) = frames.try_next() {
+            ended |= is_last(&frame);
+        }
+        ended |= streams
+            .handlers
+            .get(&self.id)
+            .is_some_and(|queue| queue.frames().any(is_last));
+        //every sender can queue one frame even if the channel is full
+        if !ended
+            && sender
+                .clone()
+                .try_send(StopFrame::new(self.id).into())
+                .is_ok()
+        {
+            streams.stopping.insert(self.id);
+        }
+    }
+}
+
+impl Drop for OpenStream {
+    fn drop(&mut self) {
+        self.streams.lock().unwrap().handlers.remove(&self.id);
+    }
+}
+
+// whether nothing follows `frame` on its stream
+fn is_last(frame: &Frame) -> bool {
+    match frame {
+        Frame::Data(data) => data.length() == 0,
+        Frame::Answer(_) | Frame::Error(_) | Frame::Stop(_) => true,
+        _ => false,
+    }
+}
+
+impl Session {
+    pub(crate) fn new(mut connection: Connection) -> Self {
+        let sender = connection.sender();
+        let streams = Arc::new(Mutex::new(Streams {
+            handlers: HashMap::new(),
+            stopping: HashSet::new(),
+            next_id: 1,
+            closed: false,
+        }));
+
+        //hand the frames of the server to the streams they are for
+        let streams_router = streams.clone();
+        let router = tokio::spawn(async move {
+            loop {
+                let delivered =
+                    poll_fn(|cx| deliver(&mut streams_router.lock().unwrap().handlers, cx));
+                let frame = tokio::select! {
+                    frame = connection.recv() => match frame {
+                        Some(frame) => frame,
+                        None => break,
+                    },
+                    _ = delivered => continue,
+                };
+                let id = frame.stream_id();
+                let mut streams = streams_router.lock().unwrap();
+                if is_last(&frame) && streams.stopping.remove(&id) {
+                    debug!("Stream {} stopped", id);
+                }
+                //a stream not being read only holds up itself
+                match streams.handlers.get_mut(&id) {
+                    Some(queue) => {
+                        if !queue.push(frame) {
+                            warn!("Stream {} exceeded its window, aborting it", id);
+                            queue.abort();
+                        }
+                    }
+                    None => debug!("Discarding {:?} for closed stream", frame),
+                }
+            }
+            //streams still waiting learn that nothing follows
+            {
+                let mut streams = streams_router.lock().unwrap();
+                streams.closed = true;
+                streams.handlers.clear();
+            }
+            connection.close().await
+        });
+
+        Session {
+            sender,
+            streams,
+            router,
+        }
+    }
+
+    // the next stream id not in use, ids are reused only after all others
+    fn open(&self) -> anyhow::Result<(OpenStream, Receiver<Frame>)> {
+        let mut streams = self.streams.lock().unwrap();
+        if streams.closed {
+            return Err(anyhow!("The connection to the server is closed"));
+        }
+        if streams.handlers.len() + streams.stopping.len() >= u16::MAX as usize {
+            return Err(anyhow!("Too many open streams"));
+        }
+        let mut id = streams.next_id;
+        while id == 0 || streams.handlers.contains_key(&id) || streams.stopping.contains(&id) {
+            id = id.wrapping_add(1);
+        }
+        streams.next_id = id.wrapping_add(1);
+        let (tx, rx) = channel(STREAM_BUFFER);
+        streams.handlers.insert(id, StreamQueue::new(tx));
+        let stream = OpenStream {
+            id,
+            streams: self.streams.clone(),
+        };
+        Ok((stream, rx))
+    }
+
+    /// Read `range` of the file at `path`, e.g. `..` for all of it.
+    ///
+    /// Fails if the server can't read the file or the range ends past its end.
+    pub async fn open_read(
+        &self,
+        path: impl AsRef<Path>,
+        range: impl RangeBounds<u64>,
+    ) -> anyhow::Result<RemoteReader> {
+        let path = path.as_ref();
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "Range bound past u64::MAX");
+        let offset = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start.checked_add(1).ok_or_else(invalid)?,
+            Bound::Unbounded => 0,
+        };
+        //a length of 0 reads up to the end of the file
+        let length = match range.end_bound() {
+            Bound::Included(&end) => Some(
+                end.checked_add(1)
+                    .ok_or_else(invalid)?
+                    .saturating_sub(offset),
+            ),
+            Bound::Excluded(&end) => Some(end.saturating_sub(offset)),
+            Bound::Unbounded => None,
+        };
+
+        let (stream, frames) = self.open()?;
+        let mut reader = RemoteReader {
+            frames,
+            sender: self.sender.clone(),
+            data: Bytes::new(),
+            offset,
+            window: offset.saturating_add(STREAM_WINDOW),
+            done: length == Some(0),
+            ended: length == Some(0),
+            stream,
+        };
+        if reader.done {
+            return Ok(reader);
+        }
+        let read_frame = ReadFrame::new(reader.stream.id, 0, offset, length.unwrap_or(0), 0, path);
+        self.sender.clone().send(read_frame.into()).await?;
+
+        //errors like a missing file come first, instead of data
+        let first = reader.frames.next().await;
+        reader
+            .receive(first)
+            .map_err(|e| anyhow!("Could not read {:?}: {}", path, e))?;
+        Ok(reader)
+    }
+
+    /// Write the file at `path`, replacing it if it exists.
+    ///
+    /// The upload is only complete once the writer is shut down without error.
+    pub async fn open_write(&self, path: impl AsRef<Path>) -> anyhow::Result<RemoteWriter> {
+        let (stream, responses) = self.open()?;
+        let write_frame = WriteFrame::new(stream.id, WriteFrame::FLAG_REPLACE, 0, 0, path.as_ref());
+        let mut sender = self.sender.clone();
+        sender.send(write_frame.into()).await?;
+        Ok(RemoteWriter {
+            sender,
+            responses,
+            offset: 0,
+            window: STREAM_WINDOW,
+            finished: false,
+            completed: false,
+            ended: false,
+            stream,
+        })
+    }
+
+    /// Entry of the file or directory at `path`, None if there is none.
+    pub async fn stat(&self, path: impl AsRef<Path>) -> anyhow::Result<Option<ListEntry>> {
+        let path = path.as_ref();
+        match self.request(|id| StatFrame::new(id, path).into()).await? {
+            Ok(entry) => Ok(ListEntry::parse_answer(&entry)
+                .context(format!("Invalid answer to stat of {:?}", path))?
+                .pop()),
+            Err(e) if e.error_code() == ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(anyhow!("Could not stat {:?}: {}", path, e.message())),
+        }
+    }
+
+    /// Entries below the directory at `path`, or those matching it as a glob.
+    ///
+    /// Directories with glob syntax in their path are listed by escaping it,
+    /// see [`crate::glob::escape`].
+    pub async fn list(&self, path: impl AsRef<Path>) -> anyhow::Result<Vec<ListEntry>> {
+        let path = path.as_ref();
+        match self.request(|id| ListFrame::new(id, path).into()).await? {
+            Ok(listing) => {
+                ListEntry::parse_answer(&listing).context(format!("Invalid listing of {:?}", path))
+            }
+            Err(e) => Err(anyhow!("Could not list {:?}: {}", path, e.message())),
+        }
+    }
+
+    /// SHA-256 of the file at `path`.
+    pub async fn checksum(&self, path: impl AsRef<Path>) -> anyhow::Result<Vec<u8>> {
+        let path = path.as_ref();
+        match self
+            .request(|id| ChecksumFrame::new(id, path).into())
+            .await?
+        {
+            Ok(checksum) => Ok(checksum),
+            Err(e) => Err(anyhow!(
+                "Could not get checksum of {:?}: {}",
+                path,
+                e.message()
+            )),
+        }
+    }
+
+    // send a command and wait for its answer, listings span several answers up to an empty one
+    async fn request<F>(&self, command: F) -> anyhow::Result<Result<Vec<u8>, ErrorFrame>>
+    where
+        F: FnOnce(u16) -> Frame,
+    {
+        let (stream, mut frames) = self.open()?;
+        let command = command(stream.id);
+        let listing = matches!(command, Frame::List(_));
+        self.sender.clone().send(command).await?;
+
+        let mut answer = Vec::new();
+        while let Some(frame) = frames.next().await {
+            match frame {
+                Frame::Answer(a) if listing && !a.payload().is_empty() => {
+                    answer.extend_from_slice(a.payload())
+                }
+                Frame::Answer(_) if listing => return Ok(Ok(answer)),
+                Frame::Answer(a) => return Ok(Ok(a.payload().to_vec())),
+                Frame::Error(e) => return Ok(Err(e)),
+                _ => warn!("Unexpected frame for a request: {:?}", frame),
+            }
+        }
+        Err(anyhow!("The connection to the server is closed"))
+    }
+
+    /// Check out from the server.
+    ///
+    /// Readers and writers still open are cut off, uploads that weren't
+    /// shut down remain incomplete.
+    pub async fn close(self) -> anyhow::Result<()> {
+        let mut sender = self.sender;
+        sender.send(Frame::Exit(ExitFrame::new())).await?;
+        drop(sender);
+        self.router.await.context("Session router panicked")?
+    }
+}
+
+fn closed(_: futures::channel::mpsc::SendError) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "The connection to the server is closed",
+    )
+}
+
+// error the server reported on a stream, or why there is no answer at all
+fn stream_error(frame: Option<Frame>) -> io::Error {
+    match frame {
+        Some(Frame::Error(e)) => io::Error::new(e.error_code().into(), e.message()),
+        Some(frame) => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected frame {:?}", frame),
+        ),
+        None => io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "The connection to the server is closed",
+        ),
+    }
+}
+
+/// Data of a remote file as the server sends it.
+///
+/// Dropping it before the end tells the server to stop sending.
+pub struct RemoteReader {
+    frames: Receiver<Frame>,
+    sender: Sender<Frame>,
+    //received but not yet read
+    data: Bytes,
+    //offset of the next data frame
+    offset: u64,
+    //how far the server may send
+    window: u64,
+    done: bool,
+    //the server sent the last frame of the stream
+    ended: bool,
+    stream: OpenStream,
+}
+
+impl RemoteReader {
+    // let the server go on once half of its window is read
+    fn poll_window(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        if self.window.saturating_sub(self.offset) > STREAM_WINDOW / 2 {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(self.sender.poll_ready(cx)).map_err(closed)?;
+        self.window = self.offset.saturating_add(STREAM_WINDOW);
+        let frame = StreamWindowFrame::new(self.stream.id, self.window);
+        self.sender.start_send(frame.into()).map_err(closed)?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn receive(&mut self, frame: Option<Frame>) -> io::Result<()> {
+        match frame {
+            Some(Frame::Data(data)) if data.offset() != self.offset => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected data at {} but got {}", self.offset, data.offset()),
+            )),
+            //an empty data frame marks the end
+            Some(Frame::Data(data)) if data.length() == 0 => {
+                self.done = true;
+                self.ended = true;
+                Ok(())
+            }
+            Some(Frame::Data(data)) => {
+                self.offset += data.length();
+                self.data = data.payload().clone();
+                Ok(())
+            }
+            frame => {
+                self.ended = frame.as_ref().is_none_or(is_last);
+                Err(stream_error(frame))
+            }
+        }
+    }
+}
+
+impl Drop for RemoteReader {
+    fn drop(&mut self) {
+        self.stream.stop(&mut self.frames, &self.sender, self.ended);
+    }
+}
+
+impl AsyncRead for RemoteReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.data.is_empty() && !this.done {
+            ready!(this.poll_window(cx))?;
+            let frame = ready!(this.frames.poll_next_unpin(cx));
+            this.receive(frame)?;
+        }
+        let n = min(this.data.len(), buf.remaining());
+        buf.put_slice(&this.data.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A new remote file, written as data frames to the server.
+///
+/// Dropping it before it is shut down tells the server to stop the upload.
+pub struct RemoteWriter {
+    sender: Sender<Frame>,
+    responses: Receiver<Frame>,
+    offset: u64,
+    //how far the server takes data, it extends it in its responses
+    window: u64,
+    //the empty data frame ending the upload was sent
+    finished: bool,
+    //the server confirmed the upload
+    completed: bool,
+    //the server answered, nothing follows
+    ended: bool,
+    stream: OpenStream,
+}
+
+impl RemoteWriter {
+    fn send(&mut self, cx: &mut TaskContext<'_>, payload: Bytes) -> Poll<io::Result<()>> {
+        ready!(self.sender.poll_ready(cx)).map_err(closed)?;
+        let frame = DataFrame::new(self.stream.id, self.offset, payload);
+        self.sender.start_send(frame.into()).map_err(closed)?;
+        Poll::Ready(Ok(()))
+    }
+
+    // the next response that isn't a window update
+    fn poll_response(&mut self, cx: &mut TaskContext<'_>) -> Poll<Option<Frame>> {
+        loop {
+            match ready!(self.responses.poll_next_unpin(cx)) {
+                Some(Frame::StreamWindow(w)) => self.window = self.window.max(w.max_offset()),
+                frame => return Poll::Ready(frame),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for RemoteWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        //the server only answers early if it rejects the upload
+        if let Poll::Ready(frame) = this.poll_response(cx) {
+            this.ended = true;
+            return Poll::Ready(Err(stream_error(frame)));
+        }
+        if this.finished {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The upload is already finished",
+            )));
+        }
+        //an empty data frame would end the upload
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        //the server extends the window in a response, which wakes us
+        if this.offset >= this.window {
+            return Poll::Pending;
+        }
+        let n = min(
+            min(buf.len(), DATA_SIZE),
+            (this.window - this.offset) as usize,
+        );
+        ready!(this.send(cx, Bytes::copy_from_slice(&buf[..n])))?;
+        this.offset += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    /// The data is handed to the connection, it is only confirmed on shutdown.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().sender)
+            .poll_flush(cx)
+            .map_err(closed)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.completed {
+            return Poll::Ready(Ok(()));
+        }
+        if !this.finished {
+            ready!(this.send(cx, Bytes::new()))?;
+            this.finished = true;
+        }
+        let frame = ready!(this.poll_response(cx));
+        this.ended = true;
+        match frame {
+            Some(Frame::Answer(_)) => {
+                this.completed = true;
+                Poll::Ready(Ok(()))
+            }
+            frame => Poll::Ready(Err(stream_error(frame))),
+        }
+    }
+}
+
+impl Drop for RemoteWriter {
+    fn drop(&mut self) {
+        self.stream
+            .stop(&mut self.responses, &self.sender, self.ended);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn_handler::connection_handler;
+    use crate::connection::Timeouts;
+    use crate::stream_handler::HandlerContext;
+    use futures::channel::mpsc::channel;
+    use std::fs;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_session() {
+        let dir = "session_test";
+        let _ = fs::remove_dir_all(dir);
+        let (client_tx, server_rx) = channel(64);
+        let (server_tx, client_rx) = channel(64);
+        tokio::spawn(connection_handler(
+            server_rx,
+            server_tx,
+            1,
+            HandlerContext::default(),
+            Timeouts::default(),
+            futures::stream::pending(),
+            futures::future::pending(),
+        ));
+        let session = Session::new(Connection::connect(client_rx, client_tx).await.unwrap());
+
+        let content: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let path = Path::new(dir).join("data.bin");
+        let mut writer = session.open_write(&path).await.unwrap();
+        writer.write_all(&content).await.unwrap();
+        writer.shutdown().await.unwrap();
+        assert_eq!(fs::read(&path).unwrap(), content);
+
+        //existing files are replaced, even by shorter ones
+        let mut writer = session.open_write(&path).await.unwrap();
+        writer.write_all(&content[..100]).await.unwrap();
+        writer.shutdown().await.unwrap();
+        assert_eq!(fs::read(&path).unwrap(), content[..100]);
+        let mut writer = session.open_write(&path).await.unwrap();
+        writer.write_all(&content).await.unwrap();
+        writer.shutdown().await.unwrap();
+        assert_eq!(fs::read(&path).unwrap(), content);
+
+        let mut data = Vec::new();
+        let mut reader = session.open_read(&path, ..).await.unwrap();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, content);
+
+        let mut data = Vec::new();
+        let mut reader = session.open_read(&path, 1000..=3999).await.unwrap();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, content[1000..4000]);
+
+        let mut reader = session.open_read(&path, 10..10).await.unwrap();
+        assert_eq!(reader.read(&mut [0; 8]).await.unwrap(), 0);
+        assert!(session.open_read(&path, 4000..6000).await.is_err());
+        let err = session.open_read(&path, ..=u64::MAX).await.err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<io::Error>().unwrap().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert!(session.open_read("session_test/missing", ..).await.is_err());
+
+        let entry = session.stat(&path).await.unwrap().unwrap();
+        assert_eq!(entry.size, 5000);
+        assert!(session
+            .stat("session_test/missing")
+            .await
+            .unwrap()
+            .is_none());
+        let entries = session.list(dir).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, Path::new("data.bin"));
+        assert_eq!(session.checksum(&path).await.unwrap().len(), 32);
+
+        //the server extends the window of longer streams
+        let content: Vec<u8> = (0..3 * STREAM_WINDOW).map(|i| (i % 241) as u8).collect();
+        let path = Path::new(dir).join("large.bin");
+        let mut writer = session.open_write(&path).await.unwrap();
+        writer.write_all(&content).await.unwrap();
+        writer.shutdown().await.unwrap();
+        let mut data = Vec::new();
+        let mut reader = session.open_read(&path, ..).await.unwrap();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, content);
+
+        session.close().await.unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stop_reader() {
+        let dir = "session_stop_test";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir(dir).unwrap();
+        let path = Path::new(dir).join("large.bin");
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &content).unwrap();
+        let (client_tx, server_rx) = channel(64);
+        let (server_tx, client_rx) = channel(64);
+        tokio::spawn(connection_handler(
+            server_rx,
+            server_tx,
+            1,
+            HandlerContext::default(),
+            Timeouts::default(),
+            futures::stream::pending(),
+            futures::future::pending(),
+        ));
+        let session = Session::new(Connection::connect(client_rx, client_tx).await.unwrap());
+
+        //the server stops sending, the id is only used again after its last frame
+        let mut reader = session.open_read(&path, ..).await.unwrap();
+        reader.read_exact(&mut [0; 1000]).await.unwrap();
+        let id = reader.stream.id;
+        drop(reader);
+        assert!(session.streams.lock().unwrap().stopping.contains(&id));
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        while !session.streams.lock().unwrap().stopping.is_empty() {
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "stream wasn't stopped"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut data = Vec::new();
+        let mut reader = session.open_read(&path, ..).await.unwrap();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, content);
+        drop(reader);
+        assert!(session.streams.lock().unwrap().stopping.is_empty());
+        session.close().await.unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_readers() {
+        let dir = "session_concurrent_test";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir(dir).unwrap();
+        let first = Path::new(dir).join("first.bin");
+        let second = Path::new(dir).join("second.bin");
+        let content: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&first, &content).unwrap();
+        fs::write(&second, &content[1000..]).unwrap();
+        let (client_tx, server_rx) = channel(64);
+        let (server_tx, client_rx) = channel(64);
+        tokio::spawn(connection_handler(
+            server_rx,
+            server_tx,
+            1,
+            HandlerContext::default(),
+            Timeouts::default(),
+            futures::stream::pending(),
+            futures::future::pending(),
+        ));
+        let session = Session::new(Connection::connect(client_rx, client_tx).await.unwrap());
+
+        //the second reader waits unread while the first one reads to the end
+        let mut first_reader = session.open_read(&first, ..).await.unwrap();
+        let mut second_reader = session.open_read(&second, ..).await.unwrap();
+        let mut data = Vec::new();
+        tokio::time::timeout(Duration::from_secs(10), first_reader.read_to_end(&mut data))
+            .await
+            .expect("unread stream held up the session")
+            .unwrap();
+        assert_eq!(data, content);
+        let mut data = Vec::new();
+        second_reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, content[1000..]);
+
+        session.close().await.unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,563 @@
+use crate::stream_handler::sha256_digest;
+use crate::wire::{unix_time, ListEntry};
+use ring::digest::Digest;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Attributes of a stored file or directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileInfo {
+    pub is_dir: bool,
+    pub size: u64,
+    /// Permission bits as on unix.
+    pub mode: u32,
+    pub mtime: SystemTime,
+}
+
+impl FileInfo {
+    /// Entry of the file under `path` in a listing.
+    pub fn to_entry(self, path: PathBuf) -> ListEntry {
+        ListEntry {
+            path,
+            is_dir: self.is_dir,
+            size: self.size,
+            mtime: unix_time(self.mtime).0,
+        }
+    }
+}
+
+/// A file opened by [`Storage::open_write`].
+pub trait FileWriter: Write + Send {
+    /// Flush the data written so far and make it durable.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Apply the permission bits and modification time of a transferred file,
+    /// once all of its data is written.
+    fn set_metadata(&mut self, mode: u32, mtime: SystemTime) -> io::Result<()>;
+}
+
+/// Files the stream handlers serve and store.
+///
+/// Paths are the ones the export resolved the paths in the commands to, so
+/// storages other than [`LocalStorage`] are used with an unrestricted export.
+pub trait Storage: Debug + Send + Sync {
+    fn stat(&self, path: &Path) -> io::Result<FileInfo>;
+
+    /// Names and attributes of the entries of the directory `path`, in no
+    /// particular order. Symlinks are left out.
+    fn list(&self, path: &Path) -> io::Result<Vec<(OsString, FileInfo)>>;
+
+    /// The bytes of the file `path` in `range`, as far as the file reaches.
+    fn open_read(&self, path: &Path, range: Range<u64>) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Open the file `path` to append to it, creating it and the directories
+    /// it is in. With `truncate` the data it already holds is discarded.
+    fn open_write(&self, path: &Path, truncate: bool) -> io::Result<Box<dyn FileWriter>>;
+
+    /// Move the file `from` to `to`, replacing the file there.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Remove the file or empty directory `path`.
+    fn delete(&self, path: &Path) -> io::Result<()>;
+
+    /// Make the entries of the directory `path` durable, e.g. a file created
+    /// or renamed in it.
+    fn sync_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// SHA-256 of the content of the file `path`.
+    fn checksum(&self, path: &Path) -> io::Result<Digest> {
+        sha256_digest(self.open_read(path, 0..u64::MAX)?)
+    }
+
+    /// Total size of the files below the directory `path`.
+    fn usage(&self, path: &Path) -> io::Result<u64> {
+        let mut size = 0;
+        for (name, info) in self.list(path)? {
+            size += match info.is_dir {
+                true => self.usage(&path.join(name))?,
+                false => info.size,
+            };
+        }
+        Ok(size)
+    }
+
+    /// Bytes that can still be stored in a file written to `path`, None if
+    /// the storage can't tell.
+    fn available_space(&self, _path: &Path) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+/// Files on the local file system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalStorage;
+
+impl FileInfo {
+    fn local(metadata: &fs::Metadata) -> io::Result<Self> {
+        #[cfg(unix)]
+        let mode = std::os::unix::fs::PermissionsExt::mode(&metadata.permissions());
+        #[cfg(not(unix))]
+        let mode = match metadata.permissions().readonly() {
+            true => 0o444,
+            false => 0o644,
+        };
+        Ok(FileInfo {
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            mode,
+            mtime: metadata.modified()?,
+        })
+    }
+}
+
+impl Storage for LocalStorage {
+    fn stat(&self, path: &Path) -> io::Result<FileInfo> {
+        FileInfo::local(&fs::metadata(path)?)
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<(OsString, FileInfo)>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_symlink() {
+                continue;
+            }
+            entries.push((entry.file_name(), FileInfo::local(&metadata)?));
+        }
+        Ok(entries)
+    }
+
+    fn open_read(&self, path: &Path, range: Range<u64>) -> io::Result<Box<dyn Read + Send>> {
+        let mut file = File::open(path)?;
+        if file.metadata()?.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is a directory", path.display()),
+            ));
+        }
+        file.seek(SeekFrom::Start(range.start))?;
+        Ok(Box::new(
+            BufReader::new(file).take(range.end.saturating_sub(range.start)),
+        ))
+    }
+
+    fn open_write(&self, path: &Path, truncate: bool) -> io::Result<Box<dyn FileWriter>> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(truncate)
+            .open(path)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Box::new(LocalWriter(BufWriter::new(file))))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        match fs::symlink_metadata(path)?.is_dir() {
+            true => fs::remove_dir(path),
+            false => fs::remove_file(path),
+        }
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        //directories can only be opened for syncing on unix
+        #[cfg(unix)]
+        match path.as_os_str().is_empty() {
+            true => File::open(".")?.sync_all()?,
+            false => File::open(path)?.sync_all()?,
+        }
+        #[cfg(not(unix))]
+        let _ = path;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        //the file and the directories it goes to may not exist yet
+        let Some(dir) = path.ancestors().skip(1).find(|p| p.is_dir()) else {
+            return Ok(None);
+        };
+        let dir = CString::new(dir.as_os_str().as_bytes())?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(dir.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        #[allow(clippy::unnecessary_cast)]
+        Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+    }
+}
+
+struct LocalWriter(BufWriter<File>);
+
+impl Write for LocalWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl FileWriter for LocalWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.0.get_ref().sync_all()
+    }
+
+    //setuid, setgid and sticky bits are never applied, only the permissions
+    fn set_metadata(&mut self, mode: u32, mtime: SystemTime) -> io::Result<()> {
+        //pending writes would change the mtime again
+        self.0.flush()?;
+        let file = self.0.get_ref();
+        file.set_modified(mtime)?;
+        #[cfg(unix)]
+        let permissions = std::os::unix::fs::PermissionsExt::from_mode(mode & 0o777);
+        #[cfg(not(unix))]
+        let permissions = {
+            let mut permissions = file.metadata()?.permissions();
+            permissions.set_readonly(mode & 0o200 == 0);
+            permissions
+        };
+        file.set_permissions(permissions)
+    }
+}
+
+/// Files kept in memory, e.g. to serve generated data.
+///
+/// Paths are taken apart into their names, so `a/./b`, `/a/b` and `a/b` are
+/// the same file. Clones share the same files.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStorage {
+    nodes: Arc<Mutex<BTreeMap<PathBuf, Node>>>,
+}
+
+#[derive(Debug)]
+enum Node {
+    Dir { mtime: SystemTime },
+    File(MemoryFile),
+}
+
+#[derive(Debug)]
+struct MemoryFile {
+    data: Vec<u8>,
+    mode: u32,
+    mtime: SystemTime,
+}
+
+impl Node {
+    fn info(&self) -> FileInfo {
+        match self {
+            Node::Dir { mtime } => FileInfo {
+                is_dir: true,
+                size: 0,
+                mode: 0o755,
+                mtime: *mtime,
+            },
+            Node::File(file) => FileInfo {
+                is_dir: false,
+                size: file.data.len() as u64,
+                mode: file.mode,
+                mtime: file.mtime,
+            },
+        }
+    }
+}
+
+//the root directory is the empty path
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+    normalized
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("No such file or directory: {}", path.display()),
+    )
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store `data` as the file `path`, replacing what was there.
+    pub fn insert(&self, path: impl AsRef<Path>, data: impl Into<Vec<u8>>) -> io::Result<()> {
+        let mut writer = self.open_write(path.as_ref(), true)?;
+        writer.write_all(&data.into())
+    }
+
+    /// Content of the file `path`.
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<Vec<u8>> {
+        match self.nodes.lock().unwrap().get(&normalize(path.as_ref())) {
+            Some(Node::File(file)) => Some(file.data.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn stat(&self, path: &Path) -> io::Result<FileInfo> {
+        let path = normalize(path);
+        if path.as_os_str().is_empty() {
+            return Ok(Node::Dir {
+                mtime: SystemTime::UNIX_EPOCH,
+            }
+            .info());
+        }
+        match self.nodes.lock().unwrap().get(&path) {
+            Some(node) => Ok(node.info()),
+            None => Err(not_found(&path)),
+        }
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<(OsString, FileInfo)>> {
+        if !self.stat(path)?.is_dir {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Not a directory: {}", path.display()),
+            ));
+        }
+        let path = normalize(path);
+        let nodes = self.nodes.lock().unwrap();
+        Ok(nodes
+            .iter()
+            .filter(|(p, _)| p.parent() == Some(&path))
+            .filter_map(|(p, node)| Some((p.file_name()?.to_os_string(), node.info())))
+            .collect())
+    }
+
+    fn open_read(&self, path: &Path, range: Range<u64>) -> io::Result<Box<dyn Read + Send>> {
+        let path = normalize(path);
+        match self.nodes.lock().unwrap().get(&path) {
+            Some(Node::File(file)) => {
+                let len = file.data.len() as u64;
+                let (start, end) = (range.start.min(len), range.end.min(len));
+                let data = file.data[start as usize..end.max(start) as usize].to_vec();
+                Ok(Box::new(Cursor::new(data)))
+            }
+            Some(Node::Dir { .. }) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is a directory", path.display()),
+            )),
+            None => Err(not_found(&path)),
+        }
+    }
+
+    fn open_write(&self, path: &Path, truncate: bool) -> io::Result<Box<dyn FileWriter>> {
+        let path = normalize(path);
+        let now = SystemTime::now();
+        let mut nodes = self.nodes.lock().unwrap();
+        for dir in path.ancestors().skip(1) {
+            if dir.as_os_str().is_empty() {
+                break;
+            }
+            match nodes.get(dir) {
+                Some(Node::Dir { .. }) => {}
+                Some(Node::File(_)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Not a directory: {}", dir.display()),
+                    ))
+                }
+                None => {
+                    nodes.insert(dir.to_path_buf(), Node::Dir { mtime: now });
+                }
+            }
+        }
+        match nodes.get_mut(&path) {
+            Some(Node::File(file)) if truncate => file.data.clear(),
+            Some(Node::File(_)) => {}
+            Some(Node::Dir { .. }) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is a directory", path.display()),
+                ))
+            }
+            None => {
+                let file = MemoryFile {
+                    data: Vec::new(),
+                    mode: 0o644,
+                    mtime: now,
+                };
+                nodes.insert(path.clone(), Node::File(file));
+            }
+        }
+        Ok(Box::new(MemoryWriter {
+            storage: self.clone(),
+            path,
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (normalize(from), normalize(to));
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get(&to) {
+            Some(Node::Dir { .. }) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is a directory", to.display()),
+            )),
+            _ => match nodes.remove(&from) {
+                Some(node @ Node::File(_)) => {
+                    nodes.insert(to, node);
+                    Ok(())
+                }
+                Some(node) => {
+                    nodes.insert(from, node);
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Only files can be renamed",
+                    ))
+                }
+                None => Err(not_found(&from)),
+            },
+        }
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.keys().any(|p| p.parent() == Some(&path)) {
+            return Err(io::Error::new(
+                io::ErrorKind::DirectoryNotEmpty,
+                format!("Directory not empty: {}", path.display()),
+            ));
+        }
+        nodes
+            .remove(&path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(&path))
+    }
+}
+
+/// Appends to a file of a [`MemoryStorage`], which sees the data right away.
+struct MemoryWriter {
+    storage: MemoryStorage,
+    path: PathBuf,
+}
+
+impl MemoryWriter {
+    fn with_file<T>(&self, f: impl FnOnce(&mut MemoryFile) -> T) -> io::Result<T> {
+        match self.storage.nodes.lock().unwrap().get_mut(&self.path) {
+            Some(Node::File(file)) => Ok(f(file)),
+            //deleted or moved while it was written
+            _ => Err(not_found(&self.path)),
+        }
+    }
+}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.with_file(|file| {
+            file.data.extend_from_slice(buf);
+            file.mtime = SystemTime::now();
+            buf.len()
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FileWriter for MemoryWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_metadata(&mut self, mode: u32, mtime: SystemTime) -> io::Result<()> {
+        self.with_file(|file| {
+            file.mode = mode & 0o777;
+            file.mtime = mtime;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_storage() {
+        let storage = MemoryStorage::new();
+        storage.insert("data/a.txt", "hello world").unwrap();
+        let mut writer = storage
+            .open_write(Path::new("/data/./b.txt"), false)
+            .unwrap();
+        writer.write_all(b"abc").unwrap();
+        writer.write_all(b"def").unwrap();
+        drop(writer);
+
+        let mut content = String::new();
+        storage
+            .open_read(Path::new("data/a.txt"), 6..100)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "world");
+        assert_eq!(storage.stat(Path::new("data/b.txt")).unwrap().size, 6);
+        assert!(storage.stat(Path::new("data")).unwrap().is_dir);
+        assert_eq!(
+            storage.stat(Path::new("missing")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        //appending continues the file, truncating starts it over
+        let mut writer = storage.open_write(Path::new("data/b.txt"), false).unwrap();
+        writer.write_all(b"g").unwrap();
+        assert_eq!(storage.get("data/b.txt").unwrap(), b"abcdefg");
+        let mut writer = storage.open_write(Path::new("data/b.txt"), true).unwrap();
+        writer.write_all(b"x").unwrap();
+        assert_eq!(storage.get("data/b.txt").unwrap(), b"x");
+
+        let mut names: Vec<OsString> = storage
+            .list(Path::new("data"))
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["a.txt", "b.txt"]);
+        assert_eq!(storage.list(Path::new("")).unwrap().len(), 1);
+        //"hello world" and "x"
+        assert_eq!(storage.usage(Path::new("")).unwrap(), 12);
+
+        storage
+            .rename(Path::new("data/b.txt"), Path::new("data/a.txt"))
+            .unwrap();
+        assert_eq!(storage.get("data/a.txt").unwrap(), b"x");
+        assert!(storage.delete(Path::new("data")).is_err());
+        storage.delete(Path::new("data/a.txt")).unwrap();
+        storage.delete(Path::new("data")).unwrap();
+        assert!(storage.list(Path::new("")).unwrap().is_empty());
+    }
+}
@@ -1,26 +1,123 @@
+use crate::acl::Acl;
+use crate::export::{Access, Export};
+use crate::glob::{self, Pattern};
+use crate::quota::Quotas;
+use crate::storage::{FileInfo, LocalStorage, Storage};
 use crate::wire::{
-    AnswerFrame, ChecksumFrame, DataFrame, ErrorFrame, Frame, ListFrame, ReadFrame, StatFrame,
-    WriteFrame,
+    AnswerFrame, ChecksumFrame, DataFrame, ErrorCode, ErrorFrame, Frame, ListEntry, ListFrame,
+    MetadataFrame, ReadFrame, StatFrame, StopFrame, StreamWindowFrame, WriteFrame, STREAM_WINDOW,
 };
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
 use futures::{Sink, SinkExt, Stream, StreamExt};
 use std::cmp::min;
 use std::fmt::Debug;
-use std::fs;
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::io::{self, Read, Write};
+use tokio::task::spawn_blocking;
 use tokio::time::timeout;
 
 use ring::digest;
 use ring::digest::{Digest, SHA256};
-use std::fs::{File, OpenOptions};
-use std::time::Duration;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use log::{debug, error, info, warn};
 
+/// When the write handler syncs written data to disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Leave it to the operating system.
+    None,
+    /// Sync once the transfer is complete, before it is reported as such.
+    #[default]
+    OnComplete,
+    /// Sync at most every given interval during the transfer and once complete.
+    Periodic(Duration),
+}
+
+impl FromStr for Durability {
+    type Err = anyhow::Error;
+
+    /// Parse `none`, `on-complete` or `periodic[:SECONDS]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "none" => Ok(Durability::None),
+            None if s == "on-complete" => Ok(Durability::OnComplete),
+            None if s == "periodic" => Ok(Durability::Periodic(Duration::from_secs(1))),
+            Some(("periodic", secs)) => Ok(Durability::Periodic(Duration::from_secs(
+                secs.parse()
+                    .map_err(|e| anyhow!("Invalid sync interval {:?}: {}", secs, e))?,
+            ))),
+            _ => Err(anyhow!("Unknown durability policy: {}", s)),
+        }
+    }
+}
+
+/// State shared by the stream handlers of a connection.
+///
+/// The default is what the client uses for its local files: no client
+/// identity, no restrictions and the local file system.
+#[derive(Clone, Debug)]
+pub struct HandlerContext {
+    pub export: Arc<Export>,
+    pub acl: Arc<Acl>,
+    pub quotas: Arc<Quotas>,
+    pub client: Option<IpAddr>,
+    /// Upload into a hidden file and move it over the target once complete.
+    pub atomic_writes: bool,
+    pub durability: Durability,
+    pub storage: Arc<dyn Storage>,
+}
+
+impl Default for HandlerContext {
+    fn default() -> Self {
+        HandlerContext {
+            export: Arc::default(),
+            acl: Arc::default(),
+            quotas: Arc::default(),
+            client: None,
+            atomic_writes: false,
+            durability: Durability::default(),
+            storage: Arc::new(LocalStorage),
+        }
+    }
+}
+
+//hidden file in the same directory that an atomic upload goes to until it is complete
+fn partial_path(target: &Path) -> PathBuf {
+    match target.file_name() {
+        Some(name) => {
+            let mut hidden = std::ffi::OsString::from(".");
+            hidden.push(name);
+            hidden.push(".rft-part");
+            target.with_file_name(hidden)
+        }
+        None => target.to_path_buf(),
+    }
+}
+
+/// CRC32 of the first `length` bytes of `reader`, as sent with a resumed ReadFrame.
+pub fn prefix_crc32<R: Read>(reader: R, length: u64) -> io::Result<u32> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut reader = reader.take(length);
+    let mut buffer = [0; 8192];
+
+    loop {
+        let count = reader.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..count]);
+    }
+
+    Ok(hasher.finalize())
+}
+
 //from rust cookbook
-#[allow(dead_code)]
-fn sha256_digest<R: Read>(mut reader: R) -> Result<Digest> {
+pub fn sha256_digest<R: Read>(mut reader: R) -> io::Result<Digest> {
     let mut context = digest::Context::new(&SHA256);
     let mut buffer = [0; 1024];
 
@@ -39,14 +136,27 @@ pub async fn read_handler<S: Sink<Frame> + Unpin>(
     mut stream: impl Stream<Item = Frame> + Unpin,
     mut sink: S,
     cmd: ReadFrame,
+    ctx: &HandlerContext,
 ) -> anyhow::Result<()>
 where
     <S as futures::Sink<Frame>>::Error: Debug,
 {
     info!("Received Read command");
     //parse path
-    let path: String = match cmd.path().to_str() {
-        Some(s) => s.into(),
+    let path: PathBuf = match cmd.path().to_str() {
+        Some(s) => match ctx.export.resolve(Path::new(s), Access::Read) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("Rejected path {}: {}", s, e);
+                sink.send(
+                    ErrorFrame::with_code(cmd.stream_id(), e.kind().into(), e.to_string().as_str())
+                        .into(),
+                )
+                .await
+                .expect("stream_handler: could not send response");
+                return Ok(());
+            }
+        },
         None => {
             sink.send(ErrorFrame::new(cmd.stream_id(), "Invalid Payload").into())
                 .await
@@ -55,54 +165,99 @@ where
         }
     };
 
-    //open file
-    let file: File = match OpenOptions::new().read(true).open(path.clone()) {
-        Ok(f) => {
-            debug!("Opened file: {}", path);
-            f
-        }
+    //get file size
+    let info: FileInfo = match ctx.storage.stat(&path) {
+        Ok(info) => info,
         Err(e) => {
             warn!("Could not open file: {}", e);
-            sink.send(ErrorFrame::new(cmd.stream_id(), e.to_string().as_str()).into())
+            sink.send(
+                ErrorFrame::with_code(cmd.stream_id(), e.kind().into(), e.to_string().as_str())
+                    .into(),
+            )
+            .await
+            .expect("stream_handler: could not send response");
+            return Ok(());
+        }
+    };
+    let file_size = info.size;
+
+    //a resumed read only continues if the reader holds a prefix of this file
+    if cmd.flags() & ReadFrame::FLAG_RESUME != 0 {
+        let crc = match cmd.offset() <= file_size {
+            true => ctx
+                .storage
+                .open_read(&path, 0..cmd.offset())
+                .and_then(|reader| prefix_crc32(reader, cmd.offset()))
+                .map(Some),
+            false => Ok(None),
+        };
+        let crc = match crc {
+            Ok(crc) => crc,
+            Err(e) => {
+                error!("Could not read {}: {}", path.display(), e);
+                sink.send(
+                    ErrorFrame::with_code(cmd.stream_id(), e.kind().into(), e.to_string().as_str())
+                        .into(),
+                )
                 .await
                 .expect("stream_handler: could not send response");
+                return Ok(());
+            }
+        };
+        if crc != Some(cmd.checksum()) {
+            warn!("Partial data of {} does not match", path.display());
+            sink.send(
+                ErrorFrame::with_code(
+                    cmd.stream_id(),
+                    ErrorCode::ChecksumMismatch,
+                    "Partial data does not match the file",
+                )
+                .into(),
+            )
+            .await
+            .expect("stream_handler: could not send response");
             return Ok(());
         }
-    };
-
-    //get file size
-    let metadata = fs::metadata(path.clone()).expect("Could not get file metadata");
-    let file_size = metadata.len();
+    }
 
     //check if trying to read past EOF
-    if cmd.offset() + cmd.length() > file_size {
+    let Some(end) = cmd
+        .offset()
+        .checked_add(cmd.length())
+        .filter(|&end| end <= file_size)
+    else {
         warn!("Trying to read past EOF");
         sink.send(ErrorFrame::new(cmd.stream_id(), "You're trying to read past EOF").into())
             .await
             .expect("stream_handler: could not send response");
         return Ok(());
-    }
+    };
 
     let read_target = match cmd.length() {
         0 => file_size,
-        _ => min(cmd.offset() + cmd.length(), file_size),
+        _ => end,
     };
 
-    //move cursor to offset
-    //TODO: may have to check manually if offset is past file size ??
-    let mut reader = BufReader::new(file);
-    match reader.seek(SeekFrom::Start(cmd.offset())) {
-        Ok(_) => {}
+    //open file at offset
+    let mut reader = match ctx.storage.open_read(&path, cmd.offset()..read_target) {
+        Ok(r) => {
+            debug!("Opened file: {}", path.display());
+            r
+        }
         Err(e) => {
-            sink.send(ErrorFrame::new(cmd.stream_id(), e.to_string().as_str()).into())
-                .await
-                .expect("stream_handler: could not send response");
+            sink.send(
+                ErrorFrame::with_code(cmd.stream_id(), e.kind().into(), e.to_string().as_str())
+                    .into(),
+            )
+            .await
+            .expect("stream_handler: could not send response");
             return Ok(());
         }
-    }
+    };
 
     //read data from file and generate data frames
     let mut last_offset = cmd.offset(); //the first byte not yet sent
+    let mut window = cmd.offset() + STREAM_WINDOW; //the first byte the reader doesn't take yet
     let mut fin = false;
     let mut read_buf = [0u8; 1024]; //TODO: which buf size to use? 128 for tests.
     loop {
@@ -111,25 +266,51 @@ where
             break;
         };
 
-        //check if we are orphaned
-        match timeout(Duration::from_micros(1), stream.next()).await {
-            Ok(_) => {
-                //stream closed, return
-                debug!("Read handler returned");
-                return Ok(());
-            }
-            Err(_) => {
-                //ok, stream not closed
+        //check if we are orphaned, or wait for the reader to extend the window
+        loop {
+            let next_frame = match last_offset < window || last_offset >= read_target {
+                true => match timeout(Duration::from_micros(1), stream.next()).await {
+                    Ok(f) => f,
+                    Err(_) => break, //ok, stream not closed
+                },
+                false => stream.next().await,
+            };
+            match next_frame {
+                Some(Frame::StreamWindow(w)) => window = window.max(w.max_offset()),
+                Some(Frame::Stop(_)) => {
+                    //the client dropped the stream, it frees the id on this last frame
+                    debug!("Read of {} stopped by the client", path.display());
+                    sink.send(StopFrame::new(cmd.stream_id()).into())
+                        .await
+                        .expect("stream_handler: could not send response");
+                    return Ok(());
+                }
+                _ => {
+                    //stream closed, return
+                    debug!("Read handler returned");
+                    return Ok(());
+                }
             }
         }
 
-        //read bytes from file into buf
-        let mut data_size = reader.read(&mut read_buf).expect("file read error");
+        //read bytes from file into buf, as far as the window goes
+        let limit = min(read_buf.len() as u64, window.saturating_sub(last_offset)) as usize;
+        let mut data_size = reader
+            .read(&mut read_buf[..limit])
+            .expect("file read error");
 
         //check if we reached read_target -> this frame is EOF
         if last_offset >= read_target {
             data_size = 0;
             fin = true;
+
+            //attributes go ahead of the final frame so the receiver has them on completion
+            if cmd.flags() & ReadFrame::FLAG_METADATA != 0 {
+                let f = MetadataFrame::new(cmd.stream_id(), info.size, info.mode, info.mtime);
+                sink.send(f.into())
+                    .await
+                    .expect("stream_handler: could not send response");
+            }
         }
 
         //check if we read past read_target in this iteration
@@ -158,14 +339,27 @@ pub async fn write_handler<S: Sink<Frame> + Unpin>(
     mut stream: impl Stream<Item = Frame> + Unpin,
     mut sink: S,
     cmd: WriteFrame,
+    ctx: &HandlerContext,
 ) -> anyhow::Result<()>
 where
     <S as futures::Sink<Frame>>::Error: Debug,
 {
     info!("Received Write command");
     //parse path
-    let path: String = match cmd.path().to_str() {
-        Some(s) => s.into(),
+    let path: PathBuf = match cmd.path().to_str() {
+        Some(s) => match ctx.export.resolve(Path::new(s), Access::Write) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("Rejected path {}: {}", s, e);
+                sink.send(
+                    ErrorFrame::with_code(cmd.stream_id(), e.kind().into(), e.to_string().as_str())
+                        .into(),
+                )
+                .await
+                .expect("stream_handler: could not send response");
+                return Ok(());
+            }
+        },
         None => {
             sink.send(ErrorFrame::new(cmd.stream_id(), "Invalid Payload").into())
                 .await
@@ -174,35 +368,115 @@ where
         }
     };
 
-    //create / open file
-    //TODO: use cmd-header.length() to check if enough disk space available
-    let file: File = match OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(false)
-        .open(path.clone())
-    {
-        Ok(f) => f,
+    //without the replace flag an existing file is only ever continued, in both modes
+    let replace = cmd.flags() & WriteFrame::FLAG_REPLACE != 0;
+    if !replace && cmd.offset() == 0 && ctx.storage.stat(&path).is_ok_and(|info| info.size > 0) {
+        warn!("Rejected upload to {}, the file exists", path.display());
+        sink.send(
+            ErrorFrame::with_code(
+                cmd.stream_id(),
+                ErrorCode::AlreadyExists,
+                "The file already exists",
+            )
+            .into(),
+        )
+        .await
+        .expect("stream_handler: could not send response");
+        return Ok(());
+    }
+
+    //reserve the announced length against quotas and free disk space before accepting any data
+    let share = ctx.export.share_for(cmd.path());
+    let reservation = ctx.quotas.reserve(
+        &*ctx.storage,
+        ctx.client,
+        share,
+        &path,
+        cmd.offset(),
+        cmd.length(),
+    );
+    let mut reservation = match reservation {
+        Ok(Some(r)) => r,
+        Ok(None) => {
+            warn!(
+                "Rejected upload of {} bytes to {}, quota exceeded",
+                cmd.length(),
+                path.display()
+            );
+            sink.send(
+                ErrorFrame::with_code(cmd.stream_id(), ErrorCode::QuotaExceeded, "Quota exceeded")
+                    .into(),
+            )
+            .await
+            .expect("stream_handler: could not send response");
+            return Ok(());
+        }
         Err(e) => {
-            sink.send(ErrorFrame::new(cmd.stream_id(), e.to_string().as_str()).into())
-                .await
-                .expect("stream_handler: could not send response");
+            sink.send(
+                ErrorFrame::with_code(cmd.stream_id(), e.kind().into(), e.to_string().as_str())
+                    .into(),
+            )
+            .await
+            .expect("stream_handler: could not send response");
+            return Ok(());
+        }
+    };
+
+    //in atomic mode data goes to a hidden file first, which is what resumes continue
+    let target = path;
+    let path = match ctx.atomic_writes {
+        true => partial_path(&target),
+        false => target.clone(),
+    };
+
+    //create / open file and the directories it is in, a fresh atomic upload discards stale partial data
+    //and a replacing one the old file
+    let truncate = cmd.offset() == 0 && (ctx.atomic_writes || replace);
+    if truncate {
+        if let Ok(stale) = ctx.storage.stat(&path) {
+            reservation.release_stored(stale.size);
+        }
+    }
+    let mut writer = match ctx.storage.open_write(&path, truncate) {
+        Ok(w) => w,
+        Err(e) => {
+            sink.send(
+                ErrorFrame::with_code(cmd.stream_id(), e.kind().into(), e.to_string().as_str())
+                    .into(),
+            )
+            .await
+            .expect("stream_handler: could not send response");
             return Ok(());
         }
     };
 
     //check if file size matches write offset
-    let metadata = fs::metadata(path.clone()).expect("Could not get file metadata");
-    if metadata.len() != cmd.offset() {
-        sink.send(ErrorFrame::new(cmd.stream_id(), "Write offset does not match file size").into())
+    match ctx.storage.stat(&path) {
+        Ok(info) if info.size == cmd.offset() => {}
+        Ok(_) => {
+            sink.send(
+                ErrorFrame::new(cmd.stream_id(), "Write offset does not match file size").into(),
+            )
             .await
             .expect("stream_handler: could not send response");
-        return Ok(());
+            return Ok(());
+        }
+        Err(e) => {
+            sink.send(
+                ErrorFrame::with_code(cmd.stream_id(), e.kind().into(), e.to_string().as_str())
+                    .into(),
+            )
+            .await
+            .expect("stream_handler: could not send response");
+            return Ok(());
+        }
     }
 
     //receive Data frames and write to file; stop if transmission complete
-    let mut writer = BufWriter::new(file);
     let mut last_offset = cmd.offset();
+    let mut window = cmd.offset() + STREAM_WINDOW;
+    let mut last_sync = Instant::now();
+    let mut file_metadata = None;
     loop {
         let next_frame = match timeout(Duration::from_secs(5), stream.next()).await {
             Ok(f) => f,
@@ -215,6 +489,21 @@ where
             }
         };
 
+        //the upload remains incomplete, like after an error
+        if let Some(Frame::Stop(_)) = next_frame {
+            debug!("Upload to {} stopped by the client", path.display());
+            sink.send(StopFrame::new(cmd.stream_id()).into())
+                .await
+                .expect("stream_handler: could not send response");
+            return Ok(());
+        }
+
+        //attributes to apply once the file is complete
+        if let Some(Frame::Metadata(m)) = next_frame {
+            file_metadata = Some(m);
+            continue;
+        }
+
         if let Some(Frame::Data(f)) = next_frame {
             //empty data frame marks end of transmission
             if f.length() == 0 {
@@ -229,16 +518,54 @@ where
                 )
                 .await
                 .expect("stream_handler: could not send Error");
-                break;
+                return Ok(());
+            }
+
+            //the announced length may have been wrong or missing
+            if !reservation.write(f.length()).unwrap_or(false) {
+                warn!("Aborted upload to {}, quota exceeded", path.display());
+                sink.send(
+                    ErrorFrame::with_code(
+                        cmd.stream_id(),
+                        ErrorCode::QuotaExceeded,
+                        "Quota exceeded",
+                    )
+                    .into(),
+                )
+                .await
+                .expect("stream_handler: could not send Error");
+                return Ok(());
             }
 
             //write data from frame to file
-            writer
-                .write_all(f.payload())
-                .expect("Could not write to BufWriter");
+            let mut result = writer.write_all(f.payload());
+            if let Durability::Periodic(interval) = ctx.durability {
+                if last_sync.elapsed() >= interval {
+                    result = result.and_then(|_| writer.sync());
+                    last_sync = Instant::now();
+                }
+            }
+            if let Err(e) = result {
+                error!("Could not write to {}: {}", path.display(), e);
+                sink.send(
+                    ErrorFrame::with_code(cmd.stream_id(), e.kind().into(), e.to_string().as_str())
+                        .into(),
+                )
+                .await
+                .expect("stream_handler: could not send Error");
+                return Ok(());
+            }
 
             //update last received frame id and offset
             last_offset += f.length();
+
+            //let the client go on once it used up half of its window
+            if window.saturating_sub(last_offset) <= STREAM_WINDOW / 2 {
+                window = last_offset + STREAM_WINDOW;
+                sink.send(StreamWindowFrame::new(cmd.stream_id(), window).into())
+                    .await
+                    .expect("stream_handler: could not send response");
+            }
         } else {
             //illegal frame or channel closed: abort transmission and leave file so client can continue later
             debug!("Write handler returned");
@@ -248,23 +575,72 @@ where
             return Ok(());
         }
     }
+
+    //flush explicitly, errors would go unnoticed if the writer was just dropped,
+    //atomic uploads always have to be on disk before they show up under the target name
+    let sync = ctx.atomic_writes || ctx.durability != Durability::None;
+    let result = file_metadata
+        .map_or(Ok(()), |m| writer.set_metadata(m.mode(), m.mtime()))
+        .and_then(|_| match sync {
+            true => writer.sync(),
+            false => writer.flush(),
+        })
+        .and_then(|_| {
+            //the file is complete, close it before it moves
+            drop(writer);
+            if ctx.atomic_writes {
+                let replaced = ctx.storage.stat(&target).map_or(0, |info| info.size);
+                ctx.storage.rename(&path, &target)?;
+                reservation.release_stored(replaced);
+            }
+            //the file may be new, its directory entry has to be durable as well
+            match (ctx.durability, target.parent()) {
+                (Durability::None, _) | (_, None) => Ok(()),
+                (_, Some(dir)) => ctx.storage.sync_dir(dir),
+            }
+        });
+    match result {
+        Ok(()) => {
+            //the peer can't tell otherwise whether the last writes succeeded
+            debug!("Completed upload to {}", target.display());
+            sink.send(AnswerFrame::new(cmd.stream_id(), Bytes::new()).into())
+                .await
+                .expect("stream_handler: could not send response");
+        }
+        Err(e) => {
+            error!("Could not complete upload to {}: {}", target.display(), e);
+            sink.send(
+                ErrorFrame::with_code(cmd.stream_id(), e.kind().into(), e.to_string().as_str())
+                    .into(),
+            )
+            .await
+            .expect("stream_handler: could not send response");
+        }
+    }
     Ok(())
 }
 
+//hash the file off the runtime, it takes a while for large files
+async fn checksum(ctx: &HandlerContext, path: &Path) -> io::Result<Digest> {
+    let path = ctx.export.resolve(path, Access::Read)?;
+    let storage = ctx.storage.clone();
+    spawn_blocking(move || storage.checksum(&path))
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)))
+}
+
 pub async fn checksum_handler<S: Sink<Frame> + Unpin>(
     mut sink: S,
     cmd: ChecksumFrame,
+    ctx: &HandlerContext,
 ) -> anyhow::Result<()>
 where
     <S as futures::Sink<Frame>>::Error: Debug,
 {
     info!("Received Checksum command");
     match cmd.path().to_str() {
-        Some(p) => match File::open(p) {
-            Ok(f) => {
-                debug!("Opened file: {}", p);
-                let reader = BufReader::new(f);
-                let digest = sha256_digest(reader)?;
+        Some(p) => match checksum(ctx, Path::new(p)).await {
+            Ok(digest) => {
                 sink.send(
                     AnswerFrame::new(cmd.stream_id(), Bytes::copy_from_slice(digest.as_ref()))
                         .into(),
@@ -274,9 +650,12 @@ where
             }
             Err(e) => {
                 warn!("Could not open file: {}", e);
-                sink.send(ErrorFrame::new(cmd.stream_id(), e.to_string().as_str()).into())
-                    .await
-                    .expect("stream_handler: could not send response");
+                sink.send(
+                    ErrorFrame::with_code(cmd.stream_id(), e.kind().into(), e.to_string().as_str())
+                        .into(),
+                )
+                .await
+                .expect("stream_handler: could not send response");
                 return Ok(());
             }
         },
@@ -291,29 +670,142 @@ where
     Ok(())
 }
 
-pub async fn stat_handler<S: Sink<Frame> + Unpin>(mut sink: S, cmd: StatFrame) -> anyhow::Result<()>
+/// Answer with the entry of a single path, in the format of a listing.
+pub async fn stat_handler<S: Sink<Frame> + Unpin>(
+    mut sink: S,
+    cmd: StatFrame,
+    ctx: &HandlerContext,
+) -> anyhow::Result<()>
 where
     <S as futures::Sink<Frame>>::Error: Debug,
 {
     info!("Received Stat command");
-    error!("Stat command not implemented");
-    sink.send(ErrorFrame::new(cmd.stream_id(), "Not implemented").into())
+    let Some(p) = cmd.path().to_str() else {
+        sink.send(ErrorFrame::new(cmd.stream_id(), "Invalid Payload").into())
+            .await
+            .expect("stream_handler: could not send response");
+        return Ok(());
+    };
+    let entry = ctx
+        .export
+        .resolve(Path::new(p), Access::Read)
+        .and_then(|path| ctx.storage.stat(&path))
+        .map(|info| info.to_entry(PathBuf::from(p)));
+
+    let response = match entry {
+        Ok(entry) => AnswerFrame::new(cmd.stream_id(), Bytes::from(entry.to_string())).into(),
+        Err(e) => {
+            warn!("Could not stat {}: {}", p, e);
+            ErrorFrame::with_code(cmd.stream_id(), e.kind().into(), e.to_string().as_str()).into()
+        }
+    };
+    sink.send(response)
         .await
         .expect("stream_handler: could not send response");
     Ok(())
 }
 
-type Type = ListFrame;
+/// Every file and directory below `root` with paths relative to it, sorted.
+///
+/// A file is listed under its name. Symlinks are not followed, they are
+/// left out.
+pub fn list_dir(storage: &dyn Storage, root: &Path) -> io::Result<Vec<ListEntry>> {
+    let info = storage.stat(root)?;
+    if !info.is_dir {
+        let name = root.file_name().map(PathBuf::from).unwrap_or_default();
+        return Ok(vec![info.to_entry(name)]);
+    }
 
-pub async fn list_handler<S: Sink<Frame> + Unpin>(mut sink: S, cmd: Type) -> anyhow::Result<()>
+    let mut entries = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for (name, info) in storage.list(&root.join(&dir))? {
+            let path = dir.join(name);
+            if info.is_dir {
+                dirs.push(path.clone());
+            }
+            entries.push(info.to_entry(path));
+        }
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Entries below the export matching a glob, listed by their path from the
+/// root of the export like the glob itself.
+pub fn list_glob(
+    storage: &dyn Storage,
+    export: &Export,
+    glob: &Path,
+) -> io::Result<Vec<ListEntry>> {
+    let (base, rest) = glob::split_base(glob);
+    let pattern: Pattern = rest
+        .to_string_lossy()
+        .parse()
+        .map_err(|e: anyhow::Error| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let dir = match base.as_os_str().is_empty() {
+        true => export.resolve(Path::new("."), Access::Read)?,
+        false => export.resolve(&base, Access::Read)?,
+    };
+    if !storage.stat(&dir)?.is_dir {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No such directory: {}", base.display()),
+        ));
+    }
+
+    let mut entries = list_dir(storage, &dir)?;
+    entries.retain(|entry| pattern.matches_path(&entry.path));
+    for entry in &mut entries {
+        entry.path = base.join(&entry.path);
+    }
+    Ok(entries)
+}
+
+pub async fn list_handler<S: Sink<Frame> + Unpin>(
+    mut sink: S,
+    cmd: ListFrame,
+    ctx: &HandlerContext,
+) -> anyhow::Result<()>
 where
     <S as futures::Sink<Frame>>::Error: Debug,
 {
     info!("Received List command");
-    error!("List command not implemented");
-    sink.send(ErrorFrame::new(cmd.stream_id(), "Not implemented").into())
-        .await
-        .expect("stream_handler: could not send response");
+    let listing = match cmd.path().to_str() {
+        Some(p) if glob::is_pattern(p) => {
+            list_glob(ctx.storage.as_ref(), &ctx.export, Path::new(p))
+        }
+        //other paths may still escape characters of glob syntax
+        Some(p) => ctx
+            .export
+            .resolve(Path::new(&glob::unescape(p)), Access::Read)
+            .and_then(|path| list_dir(ctx.storage.as_ref(), &path)),
+        None => {
+            sink.send(ErrorFrame::new(cmd.stream_id(), "Invalid Payload").into())
+                .await
+                .expect("stream_handler: could not send response");
+            return Ok(());
+        }
+    };
+
+    match listing.and_then(|entries| ListEntry::to_answers(cmd.stream_id(), &entries)) {
+        Ok(answers) => {
+            for answer in answers {
+                sink.send(answer.into())
+                    .await
+                    .expect("stream_handler: could not send response");
+            }
+        }
+        Err(e) => {
+            warn!("Could not list {}: {}", cmd.path().display(), e);
+            sink.send(
+                ErrorFrame::with_code(cmd.stream_id(), e.kind().into(), e.to_string().as_str())
+                    .into(),
+            )
+            .await
+            .expect("stream_handler: could not send response");
+        }
+    }
     Ok(())
 }
 
@@ -321,6 +813,7 @@ where
 pub async fn stream_handler<S: Sink<Frame> + Unpin>(
     mut stream: impl Stream<Item = Frame> + Unpin,
     sink: S,
+    ctx: HandlerContext,
 ) -> anyhow::Result<()>
 where
     <S as futures::Sink<Frame>>::Error: Debug,
@@ -328,11 +821,11 @@ where
     match stream.next().await {
         None => Ok(()),
         Some(frame) => match frame {
-            Frame::Read(cmd) => read_handler(stream, sink, cmd).await,
-            Frame::Write(cmd) => write_handler(stream, sink, cmd).await,
-            Frame::Checksum(cmd) => checksum_handler(sink, cmd).await,
-            Frame::Stat(cmd) => stat_handler(sink, cmd).await,
-            Frame::List(cmd) => list_handler(sink, cmd).await,
+            Frame::Read(cmd) => read_handler(stream, sink, cmd, &ctx).await,
+            Frame::Write(cmd) => write_handler(stream, sink, cmd, &ctx).await,
+            Frame::Checksum(cmd) => checksum_handler(sink, cmd, &ctx).await,
+            Frame::Stat(cmd) => stat_handler(sink, cmd, &ctx).await,
+            Frame::List(cmd) => list_handler(sink, cmd, &ctx).await,
             _ => {
                 error!("Illegal initial frame reached stream_handler");
                 Err(anyhow!("Illegal initial frame reached stream_handler"))
@@ -342,16 +835,35 @@ where
 }
 
 #[cfg(test)]
+//failures are signalled with assert!(false)
+#[allow(clippy::assertions_on_constants)]
 mod tests {
     use super::*;
+    use crate::storage::MemoryStorage;
     use crate::wire::Frame::Error;
-    use crate::wire::{ChecksumFrame, DataFrame, ReadFrame, WriteFrame};
-    use crate::wire::{Frame, Frame::Answer};
+    use crate::wire::{ChecksumFrame, DataFrame, ListFrame, ReadFrame, WriteFrame};
+    use crate::wire::{Frame, Frame::Answer, Frame::Data};
     use data_encoding::HEXLOWER;
     use futures::channel::mpsc::{channel, Receiver, Sender};
+    use std::fs::{self, File};
     use std::path::Path;
     use std::str;
 
+    #[test]
+    fn test_parse_durability() {
+        assert_eq!("none".parse::<Durability>().unwrap(), Durability::None);
+        assert_eq!(
+            "on-complete".parse::<Durability>().unwrap(),
+            Durability::OnComplete
+        );
+        assert_eq!(
+            "periodic:5".parse::<Durability>().unwrap(),
+            Durability::Periodic(Duration::from_secs(5))
+        );
+        assert!("always".parse::<Durability>().is_err());
+        assert!("periodic:soon".parse::<Durability>().is_err());
+    }
+
     #[tokio::test]
     async fn test_checksum() {
         let path = "testfile.txt";
@@ -364,7 +876,7 @@ mod tests {
                 .await
                 .unwrap();
 
-            match stream_handler(irx, otx).await {
+            match stream_handler(irx, otx, HandlerContext::default()).await {
                 Ok(()) => {
                     let af = orx.next().await.unwrap();
 
@@ -405,11 +917,11 @@ mod tests {
         {
             let (mut itx, irx): (Sender<Frame>, Receiver<Frame>) = channel(1);
             let (otx, mut orx): (Sender<Frame>, Receiver<Frame>) = channel(1);
-            itx.send(ChecksumFrame::new(420, &Path::new(path)).into())
+            itx.send(ChecksumFrame::new(420, Path::new(path)).into())
                 .await
                 .unwrap();
 
-            match stream_handler(irx, otx).await {
+            match stream_handler(irx, otx, HandlerContext::default()).await {
                 Ok(()) => {
                     let af = orx.next().await.unwrap();
 
@@ -445,10 +957,10 @@ mod tests {
 
         {
             let (mut itx, irx): (Sender<Frame>, Receiver<Frame>) = channel(5);
-            let (otx, _orx): (Sender<Frame>, Receiver<Frame>) = channel(5);
+            let (otx, mut orx): (Sender<Frame>, Receiver<Frame>) = channel(5);
 
             //send command frame
-            itx.send(WriteFrame::new(stream_id, 0, 334, Path::new(path)).into())
+            itx.send(WriteFrame::new(stream_id, 0, 0, 334, Path::new(path)).into())
                 .await
                 .unwrap();
 
@@ -467,11 +979,22 @@ mod tests {
                 .unwrap();
 
             //run handler and test whether file written
-            match stream_handler(irx, otx).await {
+            match stream_handler(irx, otx, HandlerContext::default()).await {
                 Ok(()) => {
                     //check file
                     let file_str = fs::read_to_string(path).unwrap();
                     assert_eq!(file_str, payload);
+
+                    //completion is confirmed with an empty answer
+                    match orx.next().await.unwrap() {
+                        Answer(a) => {
+                            assert_eq!(a.stream_id(), stream_id);
+                            assert!(a.payload().is_empty());
+                        }
+                        _ => {
+                            panic!()
+                        }
+                    }
                 }
                 Err(_) => {
                     assert!(false);
@@ -482,6 +1005,297 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_write_quota_exceeded() {
+        let path = "twqe_testfile.txt";
+        let context = HandlerContext {
+            quotas: Arc::new(Quotas::new(Some(100), None, Default::default())),
+            ..Default::default()
+        };
+
+        {
+            let (mut itx, irx): (Sender<Frame>, Receiver<Frame>) = channel(1);
+            let (otx, mut orx): (Sender<Frame>, Receiver<Frame>) = channel(1);
+
+            //announce more data than the maximum file size allows
+            itx.send(WriteFrame::new(420, 0, 0, 334, Path::new(path)).into())
+                .await
+                .unwrap();
+
+            match stream_handler(irx, otx, context).await {
+                Ok(()) => match orx.next().await.unwrap() {
+                    Error(e) => {
+                        assert_eq!(e.error_code(), ErrorCode::QuotaExceeded);
+                        assert!(!Path::new(path).exists());
+                    }
+                    _ => {
+                        panic!()
+                    }
+                },
+                Err(_) => {
+                    panic!();
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_atomic_write_resume() {
+        let path = "tawr_testfile.txt";
+        let partial = ".tawr_testfile.txt.rft-part";
+        let payload = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua. Ut enim ad minim veniam, quis nostrud exercitation ullamco laboris nisi ut aliquip ex ea commodo consequat.";
+        let context = HandlerContext {
+            atomic_writes: true,
+            ..Default::default()
+        };
+
+        //old version of the target and the data of an interrupted upload
+        fs::write(path, "old content").unwrap();
+        fs::write(partial, &payload.as_bytes()[..128]).unwrap();
+
+        {
+            let (mut itx, irx): (Sender<Frame>, Receiver<Frame>) = channel(5);
+            let (otx, _orx): (Sender<Frame>, Receiver<Frame>) = channel(5);
+
+            //resume at the size of the partial file
+            let rest = Bytes::copy_from_slice(&payload.as_bytes()[128..]);
+            let len = rest.len() as u64;
+            itx.send(WriteFrame::new(420, 0, 128, len, Path::new(path)).into())
+                .await
+                .unwrap();
+            itx.send(DataFrame::new(420, 128, rest).into())
+                .await
+                .unwrap();
+
+            itx.send(DataFrame::new(420, 128 + len, Bytes::default()).into())
+                .await
+                .unwrap();
+
+            match stream_handler(irx, otx, context).await {
+                Ok(()) => {
+                    assert_eq!(fs::read_to_string(path).unwrap(), payload);
+                    assert!(!Path::new(partial).exists());
+                }
+                Err(_) => {
+                    panic!();
+                }
+            }
+
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_resume() {
+        let path = "trr_testfile.txt";
+        let payload = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua. Ut enim ad minim veniam, quis nostrud exercitation ullamco laboris nisi ut aliquip ex ea commodo consequat.";
+        fs::write(path, payload).unwrap();
+        let checksum = prefix_crc32(&payload.as_bytes()[..128], 128).unwrap();
+
+        //the reader holds the first 128 bytes, only the rest is sent
+        {
+            let (mut itx, irx): (Sender<Frame>, Receiver<Frame>) = channel(5);
+            let (otx, mut orx): (Sender<Frame>, Receiver<Frame>) = channel(5);
+            let flags = ReadFrame::FLAG_RESUME;
+            itx.send(ReadFrame::new(420, flags, 128, 0, checksum, Path::new(path)).into())
+                .await
+                .unwrap();
+
+            match stream_handler(irx, otx, HandlerContext::default()).await {
+                Ok(()) => match orx.next().await.unwrap() {
+                    Data(d) => {
+                        assert_eq!(d.offset(), 128);
+                        assert_eq!(d.payload(), &payload.as_bytes()[128..]);
+                    }
+                    _ => {
+                        panic!()
+                    }
+                },
+                Err(_) => {
+                    panic!();
+                }
+            }
+        }
+
+        //the reader's data differs from the file
+        {
+            let (mut itx, irx): (Sender<Frame>, Receiver<Frame>) = channel(5);
+            let (otx, mut orx): (Sender<Frame>, Receiver<Frame>) = channel(5);
+            let flags = ReadFrame::FLAG_RESUME;
+            itx.send(ReadFrame::new(420, flags, 128, 0, !checksum, Path::new(path)).into())
+                .await
+                .unwrap();
+
+            match stream_handler(irx, otx, HandlerContext::default()).await {
+                Ok(()) => match orx.next().await.unwrap() {
+                    Error(e) => {
+                        assert_eq!(e.error_code(), ErrorCode::ChecksumMismatch);
+                    }
+                    _ => {
+                        panic!()
+                    }
+                },
+                Err(_) => {
+                    panic!();
+                }
+            }
+        }
+
+        //reading the partial data fails, a directory can't be read
+        {
+            let dir = "trr_testdir";
+            fs::create_dir_all(dir).unwrap();
+            let (mut itx, irx): (Sender<Frame>, Receiver<Frame>) = channel(5);
+            let (otx, mut orx): (Sender<Frame>, Receiver<Frame>) = channel(5);
+            let flags = ReadFrame::FLAG_RESUME;
+            itx.send(ReadFrame::new(420, flags, 0, 0, checksum, Path::new(dir)).into())
+                .await
+                .unwrap();
+
+            match stream_handler(irx, otx, HandlerContext::default()).await {
+                Ok(()) => match orx.next().await.unwrap() {
+                    Error(e) => {
+                        assert_ne!(e.error_code(), ErrorCode::ChecksumMismatch);
+                    }
+                    _ => {
+                        panic!()
+                    }
+                },
+                Err(_) => {
+                    panic!();
+                }
+            }
+            fs::remove_dir(dir).unwrap();
+        }
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_list() {
+        let dir = Path::new("tl_testdir");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir.join("sub/empty")).unwrap();
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        fs::write(dir.join("sub/b.txt"), "world!").unwrap();
+
+        {
+            let (mut itx, irx): (Sender<Frame>, Receiver<Frame>) = channel(1);
+            let (otx, orx): (Sender<Frame>, Receiver<Frame>) = channel(10);
+            itx.send(ListFrame::new(7, dir).into()).await.unwrap();
+
+            match stream_handler(irx, otx, HandlerContext::default()).await {
+                Ok(()) => {
+                    let answers: Vec<Frame> = orx.collect().await;
+                    let mut entries = Vec::new();
+                    for frame in &answers {
+                        match frame {
+                            Answer(a) => {
+                                entries.extend(ListEntry::parse_answer(a.payload()).unwrap())
+                            }
+                            _ => {
+                                panic!()
+                            }
+                        }
+                    }
+                    let listed: Vec<(&Path, bool, u64)> = entries
+                        .iter()
+                        .map(|e| (e.path.as_path(), e.is_dir, e.size))
+                        .collect();
+                    assert_eq!(listed[0], (Path::new("a.txt"), false, 5));
+                    assert_eq!(listed[1].0, Path::new("sub"));
+                    assert_eq!(listed[2], (Path::new("sub/b.txt"), false, 6));
+                    assert_eq!(listed[3].0, Path::new("sub/empty"));
+                    assert_eq!(listed.len(), 4);
+                }
+                Err(_) => {
+                    panic!();
+                }
+            }
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stat() {
+        let path = Path::new("ts_testfile");
+        fs::write(path, "hello").unwrap();
+
+        let (mut itx, irx): (Sender<Frame>, Receiver<Frame>) = channel(1);
+        let (otx, orx): (Sender<Frame>, Receiver<Frame>) = channel(10);
+        itx.send(StatFrame::new(3, path).into()).await.unwrap();
+        match stream_handler(irx, otx, HandlerContext::default()).await {
+            Ok(()) => match orx.collect::<Vec<Frame>>().await.as_slice() {
+                [Answer(a)] => {
+                    let entries = ListEntry::parse_answer(a.payload()).unwrap();
+                    assert_eq!(entries.len(), 1);
+                    assert_eq!(entries[0].path, path);
+                    assert!(!entries[0].is_dir);
+                    assert_eq!(entries[0].size, 5);
+                }
+                _ => {
+                    panic!()
+                }
+            },
+            Err(_) => {
+                panic!();
+            }
+        }
+
+        let (mut itx, irx): (Sender<Frame>, Receiver<Frame>) = channel(1);
+        let (otx, orx): (Sender<Frame>, Receiver<Frame>) = channel(10);
+        itx.send(StatFrame::new(3, Path::new("ts_missing")).into())
+            .await
+            .unwrap();
+        stream_handler(irx, otx, HandlerContext::default())
+            .await
+            .unwrap();
+        match orx.collect::<Vec<Frame>>().await.as_slice() {
+            [Error(e)] => assert_eq!(e.error_code(), ErrorCode::NotFound),
+            _ => {
+                panic!()
+            }
+        }
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_list_glob() {
+        let dir = Path::new("tlg_testdir");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir.join("data/2026-10-01")).unwrap();
+        fs::create_dir_all(dir.join("data/2026-09-30")).unwrap();
+        fs::write(dir.join("data/2026-10-01/a.parquet"), "a").unwrap();
+        fs::write(dir.join("data/2026-10-01/b.csv"), "b").unwrap();
+        fs::write(dir.join("data/2026-09-30/c.parquet"), "c").unwrap();
+        fs::write(dir.join("data/d.parquet"), "d").unwrap();
+
+        let export = Export::new(dir).unwrap();
+        let listed = |glob: &str| -> Vec<PathBuf> {
+            list_glob(&LocalStorage, &export, Path::new(glob))
+                .unwrap()
+                .into_iter()
+                .map(|e| e.path)
+                .collect()
+        };
+        assert_eq!(
+            listed("data/2026-10-*/*.parquet"),
+            vec![PathBuf::from("data/2026-10-01/a.parquet")]
+        );
+        assert_eq!(
+            listed("data/*.parquet"),
+            vec![PathBuf::from("data/d.parquet")]
+        );
+        assert_eq!(listed("data/**/*.parquet").len(), 3);
+        assert_eq!(listed("da*"), vec![PathBuf::from("data")]);
+        assert!(list_glob(&LocalStorage, &export, Path::new("missing/*")).is_err());
+        assert!(list_glob(&LocalStorage, &export, Path::new("../*")).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
     // #[tokio::test]
     #[allow(unused)]
     async fn test_read_off0_with_write() {
@@ -507,7 +1321,7 @@ mod tests {
             let mut rec = String::new();
 
             //start handler
-            match stream_handler(irx, otx).await {
+            match stream_handler(irx, otx, HandlerContext::default()).await {
                 Ok(_) => {
                     //receive three data frames + EOF, check whether contents are correct
 
@@ -568,4 +1382,156 @@ mod tests {
             fs::remove_file(path).unwrap();
         }
     }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_preserve_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::UNIX_EPOCH;
+
+        let src = "tpm_source.txt";
+        let dst = "tpm_target.txt";
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        fs::write(src, "Lorem ipsum dolor sit amet").unwrap();
+        fs::set_permissions(src, fs::Permissions::from_mode(0o640)).unwrap();
+        File::options()
+            .write(true)
+            .open(src)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        {
+            //read the source with metadata
+            let (mut itx, irx): (Sender<Frame>, Receiver<Frame>) = channel(1);
+            let (otx, orx): (Sender<Frame>, Receiver<Frame>) = channel(10);
+            itx.send(ReadFrame::new(1, ReadFrame::FLAG_METADATA, 0, 0, 0, Path::new(src)).into())
+                .await
+                .unwrap();
+            let reader = tokio::spawn(stream_handler(irx, otx, HandlerContext::default()));
+
+            //and write everything it sends to the target
+            let (mut wtx, wrx): (Sender<Frame>, Receiver<Frame>) = channel(10);
+            let (etx, _erx): (Sender<Frame>, Receiver<Frame>) = channel(10);
+            wtx.send(WriteFrame::new(1, 0, 0, 0, Path::new(dst)).into())
+                .await
+                .unwrap();
+            let writer = tokio::spawn(stream_handler(wrx, etx, HandlerContext::default()));
+            let frames: Vec<Frame> = orx.collect().await;
+            assert!(frames[frames.len() - 2].is_metadata());
+            for frame in frames {
+                wtx.send(frame).await.unwrap();
+            }
+            reader.await.unwrap().unwrap();
+            writer.await.unwrap().unwrap();
+
+            let metadata = fs::metadata(dst).unwrap();
+            assert_eq!(
+                fs::read_to_string(dst).unwrap(),
+                "Lorem ipsum dolor sit amet"
+            );
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+            assert_eq!(metadata.modified().unwrap(), mtime);
+        }
+
+        fs::remove_file(src).unwrap();
+        fs::remove_file(dst).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replace() {
+        for atomic_writes in [false, true] {
+            let storage = MemoryStorage::new();
+            storage.insert("a.txt", "old content").unwrap();
+            let ctx = HandlerContext {
+                storage: Arc::new(storage.clone()),
+                atomic_writes,
+                ..HandlerContext::default()
+            };
+
+            //an existing file is only replaced with the flag, in both modes
+            for flags in [0, WriteFrame::FLAG_REPLACE] {
+                let (mut itx, irx): (Sender<Frame>, Receiver<Frame>) = channel(5);
+                let (otx, orx): (Sender<Frame>, Receiver<Frame>) = channel(5);
+                itx.send(WriteFrame::new(1, flags, 0, 3, Path::new("a.txt")).into())
+                    .await
+                    .unwrap();
+                itx.send(DataFrame::new(1, 0, Bytes::from_static(b"new")).into())
+                    .await
+                    .unwrap();
+                itx.send(DataFrame::new(1, 3, Bytes::new()).into())
+                    .await
+                    .unwrap();
+                stream_handler(irx, otx, ctx.clone()).await.unwrap();
+                match (flags, orx.collect::<Vec<Frame>>().await.as_slice()) {
+                    (0, [Error(e)]) => assert_eq!(e.error_code(), ErrorCode::AlreadyExists),
+                    (0, frames) => panic!("unexpected {:?}", frames),
+                    (_, [Answer(_)]) => {}
+                    (_, frames) => panic!("unexpected {:?}", frames),
+                }
+            }
+            assert_eq!(storage.get("a.txt").unwrap(), b"new");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage() {
+        let storage = MemoryStorage::new();
+        storage.insert("data/a.txt", "hello").unwrap();
+        let ctx = HandlerContext {
+            storage: Arc::new(storage.clone()),
+            atomic_writes: true,
+            ..HandlerContext::default()
+        };
+
+        let (mut itx, irx): (Sender<Frame>, Receiver<Frame>) = channel(5);
+        let (otx, orx): (Sender<Frame>, Receiver<Frame>) = channel(5);
+        itx.send(WriteFrame::new(1, 0, 0, 6, Path::new("data/b.txt")).into())
+            .await
+            .unwrap();
+        itx.send(DataFrame::new(1, 0, Bytes::from_static(b"world!")).into())
+            .await
+            .unwrap();
+        itx.send(DataFrame::new(1, 6, Bytes::new()).into())
+            .await
+            .unwrap();
+        stream_handler(irx, otx, ctx.clone()).await.unwrap();
+        match orx.collect::<Vec<Frame>>().await.as_slice() {
+            [Answer(a)] => assert!(a.payload().is_empty()),
+            frames => panic!("unexpected {:?}", frames),
+        }
+        assert_eq!(storage.get("data/b.txt").unwrap(), b"world!");
+        assert!(storage.get("data/.b.txt.rft-part").is_none());
+
+        let (mut itx, irx): (Sender<Frame>, Receiver<Frame>) = channel(1);
+        let (otx, orx): (Sender<Frame>, Receiver<Frame>) = channel(10);
+        itx.send(ReadFrame::new(2, 0, 1, 3, 0, Path::new("data/b.txt")).into())
+            .await
+            .unwrap();
+        stream_handler(irx, otx, ctx.clone()).await.unwrap();
+        let data: Vec<u8> = orx
+            .filter_map(|f| async move {
+                match f {
+                    Data(d) => Some(d.payload().to_vec()),
+                    _ => None,
+                }
+            })
+            .concat()
+            .await;
+        assert_eq!(data, b"orl");
+
+        let (mut itx, irx): (Sender<Frame>, Receiver<Frame>) = channel(1);
+        let (otx, orx): (Sender<Frame>, Receiver<Frame>) = channel(10);
+        itx.send(ListFrame::new(3, Path::new("data")).into())
+            .await
+            .unwrap();
+        stream_handler(irx, otx, ctx).await.unwrap();
+        let listing: Vec<Frame> = orx.collect().await;
+        let Answer(a) = &listing[0] else {
+            panic!("unexpected {:?}", listing)
+        };
+        let entries = ListEntry::parse_answer(a.payload()).unwrap();
+        let sizes: Vec<(PathBuf, u64)> = entries.into_iter().map(|e| (e.path, e.size)).collect();
+        assert_eq!(sizes, vec![("a.txt".into(), 5), ("b.txt".into(), 6)]);
+    }
 }
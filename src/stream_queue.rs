@@ -0,0 +1,139 @@
+use crate::wire::{Frame, STREAM_WINDOW};
+use futures::channel::mpsc::Sender;
+use std::collections::{HashMap, VecDeque};
+use std::task::{Context, Poll};
+
+/// Frames on their way to the handler of a stream.
+///
+/// The peer may only send as much data as the window of the stream allows,
+/// so a slow handler doesn't hold up the other streams of the connection.
+/// The frames move on with [`deliver`] as the handler takes them.
+pub struct StreamQueue {
+    handler: Sender<Frame>,
+    frames: VecDeque<Frame>,
+    //data in `frames`
+    queued: u64,
+}
+
+impl StreamQueue {
+    pub fn new(handler: Sender<Frame>) -> Self {
+        StreamQueue {
+            handler,
+            frames: VecDeque::new(),
+            queued: 0,
+        }
+    }
+
+    /// Queue `frame`, false if it exceeds the window of the stream.
+    pub fn push(&mut self, frame: Frame) -> bool {
+        if let Frame::Data(data) = &frame {
+            self.queued += data.length();
+            if self.queued > STREAM_WINDOW {
+                return false;
+            }
+        }
+        self.frames.push_back(frame);
+        true
+    }
+
+    /// Frames not yet taken by the handler.
+    pub fn frames(&self) -> impl Iterator<Item = &Frame> {
+        self.frames.iter()
+    }
+
+    /// Whether the handler stopped taking frames.
+    pub fn is_closed(&self) -> bool {
+        self.handler.is_closed()
+    }
+
+    /// Drop the queued frames, the handler learns from its closed channel
+    /// that the stream failed.
+    pub fn abort(&mut self) {
+        self.frames.clear();
+        self.queued = 0;
+        self.handler.close_channel();
+    }
+
+    // hand queued frames to the handler as far as it takes them, ready once any moved on
+    fn poll_deliver(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut delivered = false;
+        while let Some(frame) = self.frames.front() {
+            match self.handler.poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    if let Frame::Data(data) = frame {
+                        self.queued -= data.length();
+                    }
+                    let frame = self.frames.pop_front().expect("queue is not empty");
+                    //the handler may finish in the meantime, like when it's closed
+                    let _ = self.handler.start_send(frame);
+                }
+                //the handler finished, nothing reaches it anymore
+                Poll::Ready(Err(_)) => {
+                    self.frames.clear();
+                    self.queued = 0;
+                }
+                Poll::Pending => break,
+            }
+            delivered = true;
+        }
+        match delivered {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    }
+}
+
+/// Hand the queued frames to their handlers, ready once frames moved on in
+/// any of the queues.
+pub fn deliver(queues: &mut HashMap<u16, StreamQueue>, cx: &mut Context<'_>) -> Poll<()> {
+    let mut delivered = false;
+    for queue in queues.values_mut() {
+        delivered |= queue.poll_deliver(cx).is_ready();
+    }
+    match delivered {
+        true => Poll::Ready(()),
+        false => Poll::Pending,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::DataFrame;
+    use bytes::Bytes;
+    use futures::channel::mpsc::channel;
+    use futures::FutureExt;
+    use std::future::poll_fn;
+
+    fn data(stream_id: u16, offset: u64) -> Frame {
+        DataFrame::new(stream_id, offset, Bytes::from(vec![0; 1024])).into()
+    }
+
+    #[test]
+    fn test_stream_queues() {
+        let mut queues = HashMap::new();
+        let (tx, _stalled_rx) = channel(8);
+        let mut stalled = StreamQueue::new(tx);
+        let (tx, mut other_rx) = channel(8);
+        let mut other = StreamQueue::new(tx);
+        for offset in (0..STREAM_WINDOW).step_by(1024) {
+            assert!(stalled.push(data(1, offset)));
+        }
+        //the peer ignored the window
+        assert!(!stalled.push(data(1, STREAM_WINDOW)));
+        assert!(other.push(data(2, 0)));
+        queues.insert(1, stalled);
+        queues.insert(2, other);
+
+        //the handler of stream 1 doesn't take its frames, stream 2 gets them anyway
+        poll_fn(|cx| deliver(&mut queues, cx))
+            .now_or_never()
+            .unwrap();
+        assert!(other_rx.try_next().unwrap().unwrap().is_data());
+        assert!(queues[&1].frames().next().is_some());
+        assert!(queues[&2].frames().next().is_none());
+        assert!(poll_fn(|cx| deliver(&mut queues, cx))
+            .now_or_never()
+            .is_none());
+    }
+}
@@ -0,0 +1,156 @@
+use crate::wire::ListEntry;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Which way a sync copies the tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncDirection {
+    Download,
+    Upload,
+}
+
+/// A directory tree made identical to the one on the other side.
+///
+/// Only files that are missing or changed are transferred, with `delete`
+/// entries the source doesn't have are removed as well.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncTransfer {
+    pub local: PathBuf,
+    pub remote: PathBuf,
+    pub direction: SyncDirection,
+    pub delete: bool,
+}
+
+impl SyncTransfer {
+    pub fn new(local: PathBuf, remote: PathBuf, direction: SyncDirection, delete: bool) -> Self {
+        SyncTransfer {
+            local,
+            remote,
+            direction,
+            delete,
+        }
+    }
+}
+
+/// Differences between a source and a destination tree, with paths relative
+/// to the trees.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Comparison {
+    /// Files missing at the destination or with another size.
+    pub changed: Vec<PathBuf>,
+    /// Files of the same size but another modification time, their checksums decide.
+    pub uncertain: Vec<PathBuf>,
+    /// Directories missing at the destination.
+    pub missing_dirs: Vec<PathBuf>,
+    /// Entries only the destination has, without those below such a directory.
+    pub extraneous: Vec<PathBuf>,
+    /// Paths that are a file on one side and a directory on the other.
+    pub conflicts: Vec<PathBuf>,
+    pub unchanged: usize,
+}
+
+/// Compare the listings of the source and the destination tree.
+pub fn compare(source: &[ListEntry], destination: &[ListEntry]) -> Comparison {
+    let mut comparison = Comparison::default();
+    let existing: HashMap<&Path, &ListEntry> = destination
+        .iter()
+        .map(|entry| (entry.path.as_path(), entry))
+        .collect();
+    let mut skipped = HashSet::new();
+
+    for entry in source {
+        if entry.path.ancestors().any(|p| skipped.contains(p)) {
+            continue;
+        }
+        match (entry.is_dir, existing.get(entry.path.as_path())) {
+            (true, None) => comparison.missing_dirs.push(entry.path.clone()),
+            (false, None) => comparison.changed.push(entry.path.clone()),
+            (true, Some(other)) if other.is_dir => {}
+            (false, Some(other)) if !other.is_dir && other.size != entry.size => {
+                comparison.changed.push(entry.path.clone())
+            }
+            (false, Some(other)) if !other.is_dir && other.mtime != entry.mtime => {
+                comparison.uncertain.push(entry.path.clone())
+            }
+            (false, Some(other)) if !other.is_dir => comparison.unchanged += 1,
+            (_, Some(_)) => {
+                comparison.conflicts.push(entry.path.clone());
+                skipped.insert(entry.path.as_path());
+            }
+        }
+    }
+
+    let wanted: HashSet<&Path> = source.iter().map(|entry| entry.path.as_path()).collect();
+    let mut extraneous = HashSet::new();
+    for entry in destination {
+        if entry
+            .path
+            .ancestors()
+            .any(|p| extraneous.contains(p) || skipped.contains(p))
+        {
+            continue;
+        }
+        if !wanted.contains(entry.path.as_path()) {
+            comparison.extraneous.push(entry.path.clone());
+            extraneous.insert(entry.path.as_path());
+        }
+    }
+    comparison
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, is_dir: bool, size: u64, mtime: i64) -> ListEntry {
+        ListEntry {
+            path: path.into(),
+            is_dir,
+            size,
+            mtime,
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let source = [
+            entry("a", true, 0, 0),
+            entry("a/new.csv", false, 5, 10),
+            entry("a/same.csv", false, 5, 10),
+            entry("a/grown.csv", false, 6, 10),
+            entry("a/touched.csv", false, 5, 11),
+            entry("b", true, 0, 0),
+            entry("b/c.csv", false, 1, 1),
+            entry("d", false, 1, 1),
+        ];
+        let destination = [
+            entry("a", true, 0, 0),
+            entry("a/same.csv", false, 5, 10),
+            entry("a/grown.csv", false, 5, 10),
+            entry("a/touched.csv", false, 5, 10),
+            entry("a/old.csv", false, 5, 10),
+            entry("d", true, 0, 0),
+            entry("d/e.csv", false, 1, 1),
+            entry("x", true, 0, 0),
+            entry("x/y.csv", false, 1, 1),
+        ];
+        let comparison = compare(&source, &destination);
+        assert_eq!(
+            comparison.changed,
+            vec![
+                PathBuf::from("a/new.csv"),
+                "a/grown.csv".into(),
+                "b/c.csv".into()
+            ]
+        );
+        assert_eq!(comparison.uncertain, vec![PathBuf::from("a/touched.csv")]);
+        assert_eq!(comparison.missing_dirs, vec![PathBuf::from("b")]);
+        assert_eq!(
+            comparison.extraneous,
+            vec![PathBuf::from("a/old.csv"), "x".into()]
+        );
+        assert_eq!(comparison.conflicts, vec![PathBuf::from("d")]);
+        assert_eq!(comparison.unchanged, 1);
+        assert_eq!(compare(&source, &source).unchanged, 6);
+    }
+}
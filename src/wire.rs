@@ -1,8 +1,10 @@
 use anyhow::anyhow;
 use bytes::{Bytes, BytesMut};
+use std::fmt::Debug;
 use std::mem::size_of;
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
-use std::{fmt::Debug, path::Path};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 const VERSION: u8 = 1;
@@ -21,6 +23,13 @@ pub trait Size {
     fn size(&self) -> usize;
 }
 
+/// Largest packet the server assembles, small enough to avoid IP fragmentation.
+pub const MAX_PACKET_SIZE: usize = 1280;
+
+/// Data that may be sent on a stream past the offset of its command before
+/// the receiver extends it with a [`StreamWindowFrame`].
+pub const STREAM_WINDOW: u64 = 65536;
+
 #[derive(Debug, AsBytes, FromZeroes, FromBytes)]
 #[repr(C, packed)]
 pub struct PacketHeader {
@@ -395,11 +404,60 @@ impl Debug for AnswerFrame {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorCode {
+    Unspecified = 0,
+    PermissionDenied = 1,
+    NotFound = 2,
+    QuotaExceeded = 3,
+    ChecksumMismatch = 4,
+    AlreadyExists = 5,
+}
+
+impl ErrorCode {
+    pub fn from_u8(code: u8) -> Self {
+        match code {
+            1 => ErrorCode::PermissionDenied,
+            2 => ErrorCode::NotFound,
+            3 => ErrorCode::QuotaExceeded,
+            4 => ErrorCode::ChecksumMismatch,
+            5 => ErrorCode::AlreadyExists,
+            _ => ErrorCode::Unspecified,
+        }
+    }
+}
+
+impl From<std::io::ErrorKind> for ErrorCode {
+    fn from(kind: std::io::ErrorKind) -> Self {
+        match kind {
+            std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            std::io::ErrorKind::NotFound => ErrorCode::NotFound,
+            std::io::ErrorKind::AlreadyExists => ErrorCode::AlreadyExists,
+            _ => ErrorCode::Unspecified,
+        }
+    }
+}
+
+impl From<ErrorCode> for std::io::ErrorKind {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::PermissionDenied => std::io::ErrorKind::PermissionDenied,
+            ErrorCode::NotFound => std::io::ErrorKind::NotFound,
+            ErrorCode::QuotaExceeded => std::io::ErrorKind::StorageFull,
+            ErrorCode::ChecksumMismatch => std::io::ErrorKind::InvalidData,
+            ErrorCode::AlreadyExists => std::io::ErrorKind::AlreadyExists,
+            ErrorCode::Unspecified => std::io::ErrorKind::Other,
+        }
+    }
+}
+
 #[derive(Debug, AsBytes, FromZeroes, FromBytes)]
 #[repr(C, packed)]
 pub struct ErrorHeader {
     pub type_id: u8,
     pub stream_id: u16,
+    pub error_code: u8,
 }
 
 #[derive(Clone)]
@@ -419,9 +477,14 @@ impl ErrorFrame {
     const TYPE_ID: u8 = 5;
 
     pub fn new(stream_id: u16, message: &str) -> Self {
+        Self::with_code(stream_id, ErrorCode::Unspecified, message)
+    }
+
+    pub fn with_code(stream_id: u16, error_code: ErrorCode, message: &str) -> Self {
         let header = ErrorHeader {
             type_id: Self::TYPE_ID,
             stream_id,
+            error_code: error_code as u8,
         };
         let header_bytes = BytesMut::from(header.as_bytes()).into();
         let payload_bytes = Bytes::copy_from_slice(message.as_bytes());
@@ -443,6 +506,10 @@ impl ErrorFrame {
         self.header().stream_id
     }
 
+    pub fn error_code(&self) -> ErrorCode {
+        ErrorCode::from_u8(self.header().error_code)
+    }
+
     pub fn message(&self) -> &str {
         from_utf8(self.payload_bytes.as_ref()).expect("Failed to parse message")
     }
@@ -476,6 +543,7 @@ impl Debug for ErrorFrame {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Error")
             .field("stream_id", &self.stream_id())
+            .field("error_code", &self.error_code())
             .field("message", &self.message())
             .finish()
     }
@@ -493,6 +561,20 @@ fn u64_to_six_u8(value: u64) -> [u8; 6] {
     result
 }
 
+//times before the epoch are negative seconds plus positive nanos
+pub(crate) fn unix_time(time: SystemTime) -> (i64, u32) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(e) => {
+            let d = e.duration();
+            match d.subsec_nanos() {
+                0 => (-(d.as_secs() as i64), 0),
+                n => (-(d.as_secs() as i64) - 1, 1_000_000_000 - n),
+            }
+        }
+    }
+}
+
 #[derive(Debug, AsBytes, FromZeroes, FromBytes)]
 #[repr(C, packed)]
 pub struct DataHeader {
@@ -617,6 +699,11 @@ impl Size for ReadFrame {
 
 impl ReadFrame {
     const TYPE_ID: u8 = 7;
+    /// Ask for a MetadataFrame ahead of the final DataFrame.
+    pub const FLAG_METADATA: u8 = 0x01;
+    /// Resume a transfer: the checksum is the CRC32 of the first offset bytes
+    /// the reader already holds and the read only proceeds if they match.
+    pub const FLAG_RESUME: u8 = 0x02;
 
     pub fn new(
         stream_id: u16,
@@ -721,6 +808,7 @@ impl Debug for ReadFrame {
 pub struct WriteHeader {
     pub type_id: u8,
     pub stream_id: u16,
+    pub flags: u8,
     pub offset: [u8; 6],
     pub length: [u8; 6],
 }
@@ -740,11 +828,15 @@ impl Size for WriteFrame {
 
 impl WriteFrame {
     const TYPE_ID: u8 = 8;
+    /// Replace an existing file instead of continuing it, an offset of 0
+    /// is refused without it if the file isn't empty.
+    pub const FLAG_REPLACE: u8 = 0x01;
 
-    pub fn new(stream_id: u16, offset: u64, length: u64, path: &Path) -> Self {
+    pub fn new(stream_id: u16, flags: u8, offset: u64, length: u64, path: &Path) -> Self {
         let header = WriteHeader {
             type_id: Self::TYPE_ID,
             stream_id,
+            flags,
             offset: u64_to_six_u8(offset),
             length: u64_to_six_u8(length),
         };
@@ -772,6 +864,10 @@ impl WriteFrame {
         self.header().stream_id
     }
 
+    pub fn flags(&self) -> u8 {
+        self.header().flags
+    }
+
     pub fn offset(&self) -> u64 {
         six_u8_to_u64(&self.header().offset)
     }
@@ -813,6 +909,7 @@ impl Debug for WriteFrame {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Write")
             .field("stream_id", &self.stream_id())
+            .field("flags", &self.flags())
             .field("offset", &self.offset())
             .field("length", &self.length())
             .field("path", &self.path())
@@ -1091,6 +1188,488 @@ impl Debug for ListFrame {
     }
 }
 
+/// Entry of the answer to a List command.
+///
+/// Entries are sent as `kind size mtime path` lines, kind being `f` or `d`
+/// and mtime in seconds since the epoch, over as many AnswerFrames as they
+/// need. An empty AnswerFrame ends the listing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListEntry {
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub size: u64,
+    pub mtime: i64,
+}
+
+impl ListEntry {
+    /// Largest payload of the AnswerFrames a listing is split into.
+    pub const MAX_ANSWER_SIZE: usize = 1024;
+
+    pub fn new(path: PathBuf, metadata: &std::fs::Metadata) -> std::io::Result<Self> {
+        Ok(ListEntry {
+            path,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            mtime: unix_time(metadata.modified()?).0,
+        })
+    }
+
+    /// Encode `entries` into AnswerFrames, including the empty one at the end.
+    ///
+    /// Fails if an entry doesn't fit into an AnswerFrame on its own or its
+    /// path contains a line break, leaving it out would make the peer take
+    /// the listing for complete.
+    pub fn to_answers(stream_id: u16, entries: &[ListEntry]) -> std::io::Result<Vec<AnswerFrame>> {
+        let mut answers = Vec::new();
+        let mut payload = BytesMut::new();
+        for entry in entries {
+            let line = entry.to_string();
+            if line.len() > Self::MAX_ANSWER_SIZE || line[..line.len() - 1].contains('\n') {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Can't list {:?}, its path is too long or contains a line break",
+                        entry.path
+                    ),
+                ));
+            }
+            if payload.len() + line.len() > Self::MAX_ANSWER_SIZE {
+                answers.push(AnswerFrame::new(stream_id, payload.split().freeze()));
+            }
+            payload.extend_from_slice(line.as_bytes());
+        }
+        if !payload.is_empty() {
+            answers.push(AnswerFrame::new(stream_id, payload.freeze()));
+        }
+        answers.push(AnswerFrame::new(stream_id, Bytes::new()));
+        Ok(answers)
+    }
+
+    /// Parse the entries in the payload of an AnswerFrame.
+    pub fn parse_answer(payload: &[u8]) -> Result<Vec<ListEntry>, anyhow::Error> {
+        from_utf8(payload)?.lines().map(str::parse).collect()
+    }
+}
+
+impl std::fmt::Display for ListEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.is_dir {
+            true => 'd',
+            false => 'f',
+        };
+        writeln!(
+            f,
+            "{} {} {} {}",
+            kind,
+            self.size,
+            self.mtime,
+            self.path.display()
+        )
+    }
+}
+
+impl std::str::FromStr for ListEntry {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.splitn(4, ' ');
+        let mut next = || {
+            fields
+                .next()
+                .ok_or_else(|| anyhow!("Invalid list entry: {}", s))
+        };
+        let is_dir = match next()? {
+            "d" => true,
+            "f" => false,
+            kind => return Err(anyhow!("Invalid entry kind: {}", kind)),
+        };
+        Ok(ListEntry {
+            is_dir,
+            size: next()?.parse()?,
+            mtime: next()?.parse()?,
+            path: PathBuf::from(next()?),
+        })
+    }
+}
+
+#[derive(Debug, AsBytes, FromZeroes, FromBytes)]
+#[repr(C, packed)]
+pub struct MetadataHeader {
+    pub type_id: u8,
+    pub stream_id: u16,
+    pub size: [u8; 6],
+    pub mode: u32,
+    pub mtime_secs: i64,
+    pub mtime_nanos: u32,
+}
+
+/// Attributes of a file, sent ahead of the final DataFrame of a transfer so
+/// the receiving side can apply them once the file is complete.
+#[derive(Clone)]
+pub struct MetadataFrame {
+    bytes: Bytes,
+}
+
+impl Size for MetadataFrame {
+    #[inline(always)]
+    fn size(&self) -> usize {
+        size_of::<MetadataHeader>()
+    }
+}
+
+impl MetadataFrame {
+    const TYPE_ID: u8 = 12;
+
+    pub fn new(stream_id: u16, size: u64, mode: u32, mtime: SystemTime) -> Self {
+        let (mtime_secs, mtime_nanos) = unix_time(mtime);
+        let header = MetadataHeader {
+            type_id: Self::TYPE_ID,
+            stream_id,
+            size: u64_to_six_u8(size),
+            mode,
+            mtime_secs,
+            mtime_nanos,
+        };
+        let bytes = BytesMut::from(AsBytes::as_bytes(&header)).into();
+        MetadataFrame { bytes }
+    }
+
+    pub fn header(&self) -> &MetadataHeader {
+        MetadataHeader::ref_from(self.bytes.as_ref()).expect("Failed to reference MetadataHeader")
+    }
+
+    pub fn type_id(&self) -> u8 {
+        self.header().type_id
+    }
+
+    pub fn stream_id(&self) -> u16 {
+        self.header().stream_id
+    }
+
+    pub fn file_size(&self) -> u64 {
+        six_u8_to_u64(&self.header().size)
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.header().mode
+    }
+
+    pub fn mtime(&self) -> SystemTime {
+        let secs = self.header().mtime_secs;
+        let nanos = Duration::from_nanos(self.header().mtime_nanos as u64);
+        match secs {
+            0.. => UNIX_EPOCH + Duration::from_secs(secs as u64) + nanos,
+            _ => UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + nanos,
+        }
+    }
+}
+
+impl Parse for MetadataFrame {
+    fn parse(bytes: &mut Bytes) -> Result<Frame, anyhow::Error> {
+        // TODO bounds check
+        let bytes = bytes.split_to(size_of::<MetadataHeader>());
+        Ok(MetadataFrame { bytes }.into())
+    }
+}
+
+impl Assemble for MetadataFrame {
+    fn assemble(&self) -> BytesMut {
+        self.bytes.clone().into()
+    }
+}
+
+impl Debug for MetadataFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Metadata")
+            .field("stream_id", &self.stream_id())
+            .field("file_size", &self.file_size())
+            .field("mode", &format_args!("{:o}", self.mode()))
+            .field("mtime", &self.mtime())
+            .finish()
+    }
+}
+
+#[derive(Debug, AsBytes, FromZeroes, FromBytes)]
+#[repr(C, packed)]
+pub struct PathChallengeHeader {
+    pub type_id: u8,
+    pub nonce: u64,
+}
+
+/// Random nonce sent to a new address of the peer, which has to echo it in a
+/// PathResponseFrame from there before packets are sent to it.
+#[derive(Clone)]
+pub struct PathChallengeFrame {
+    bytes: Bytes,
+}
+
+impl Size for PathChallengeFrame {
+    #[inline(always)]
+    fn size(&self) -> usize {
+        size_of::<PathChallengeHeader>()
+    }
+}
+
+impl PathChallengeFrame {
+    const TYPE_ID: u8 = 13;
+
+    pub fn new(nonce: u64) -> Self {
+        let header = PathChallengeHeader {
+            type_id: Self::TYPE_ID,
+            nonce,
+        };
+        let bytes = BytesMut::from(AsBytes::as_bytes(&header)).into();
+        PathChallengeFrame { bytes }
+    }
+
+    pub fn header(&self) -> &PathChallengeHeader {
+        PathChallengeHeader::ref_from(self.bytes.as_ref())
+            .expect("Failed to reference PathChallengeHeader")
+    }
+
+    pub fn type_id(&self) -> u8 {
+        self.header().type_id
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.header().nonce
+    }
+}
+
+impl Parse for PathChallengeFrame {
+    fn parse(bytes: &mut Bytes) -> Result<Frame, anyhow::Error> {
+        // TODO bounds check
+        let bytes = bytes.split_to(size_of::<PathChallengeHeader>());
+        Ok(PathChallengeFrame { bytes }.into())
+    }
+}
+
+impl Assemble for PathChallengeFrame {
+    fn assemble(&self) -> BytesMut {
+        self.bytes.clone().into()
+    }
+}
+
+impl Debug for PathChallengeFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PathChallenge")
+            .field("nonce", &self.nonce())
+            .finish()
+    }
+}
+
+#[derive(Debug, AsBytes, FromZeroes, FromBytes)]
+#[repr(C, packed)]
+pub struct PathResponseHeader {
+    pub type_id: u8,
+    pub nonce: u64,
+}
+
+/// Echo of the nonce of a PathChallengeFrame.
+#[derive(Clone)]
+pub struct PathResponseFrame {
+    bytes: Bytes,
+}
+
+impl Size for PathResponseFrame {
+    #[inline(always)]
+    fn size(&self) -> usize {
+        size_of::<PathResponseHeader>()
+    }
+}
+
+impl PathResponseFrame {
+    const TYPE_ID: u8 = 14;
+
+    pub fn new(nonce: u64) -> Self {
+        let header = PathResponseHeader {
+            type_id: Self::TYPE_ID,
+            nonce,
+        };
+        let bytes = BytesMut::from(AsBytes::as_bytes(&header)).into();
+        PathResponseFrame { bytes }
+    }
+
+    pub fn header(&self) -> &PathResponseHeader {
+        PathResponseHeader::ref_from(self.bytes.as_ref())
+            .expect("Failed to reference PathResponseHeader")
+    }
+
+    pub fn type_id(&self) -> u8 {
+        self.header().type_id
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.header().nonce
+    }
+}
+
+impl Parse for PathResponseFrame {
+    fn parse(bytes: &mut Bytes) -> Result<Frame, anyhow::Error> {
+        // TODO bounds check
+        let bytes = bytes.split_to(size_of::<PathResponseHeader>());
+        Ok(PathResponseFrame { bytes }.into())
+    }
+}
+
+impl Assemble for PathResponseFrame {
+    fn assemble(&self) -> BytesMut {
+        self.bytes.clone().into()
+    }
+}
+
+impl Debug for PathResponseFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PathResponse")
+            .field("nonce", &self.nonce())
+            .finish()
+    }
+}
+
+#[derive(Debug, AsBytes, FromZeroes, FromBytes)]
+#[repr(C, packed)]
+pub struct StopHeader {
+    pub type_id: u8,
+    pub stream_id: u16,
+}
+
+/// Tells the peer to stop sending on a stream, which it confirms with a
+/// StopFrame as the last frame of the stream.
+#[derive(Clone)]
+pub struct StopFrame {
+    bytes: Bytes,
+}
+
+impl Size for StopFrame {
+    #[inline(always)]
+    fn size(&self) -> usize {
+        size_of::<StopHeader>()
+    }
+}
+
+impl StopFrame {
+    const TYPE_ID: u8 = 15;
+
+    pub fn new(stream_id: u16) -> Self {
+        let header = StopHeader {
+            type_id: Self::TYPE_ID,
+            stream_id,
+        };
+        let bytes = BytesMut::from(AsBytes::as_bytes(&header)).into();
+        StopFrame { bytes }
+    }
+
+    pub fn header(&self) -> &StopHeader {
+        StopHeader::ref_from(self.bytes.as_ref()).expect("Failed to reference StopHeader")
+    }
+
+    pub fn type_id(&self) -> u8 {
+        self.header().type_id
+    }
+
+    pub fn stream_id(&self) -> u16 {
+        self.header().stream_id
+    }
+}
+
+impl Parse for StopFrame {
+    fn parse(bytes: &mut Bytes) -> Result<Frame, anyhow::Error> {
+        // TODO bounds check
+        let bytes = bytes.split_to(size_of::<StopHeader>());
+        Ok(StopFrame { bytes }.into())
+    }
+}
+
+impl Assemble for StopFrame {
+    fn assemble(&self) -> BytesMut {
+        self.bytes.clone().into()
+    }
+}
+
+impl Debug for StopFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Stop")
+            .field("stream_id", &self.stream_id())
+            .finish()
+    }
+}
+
+#[derive(Debug, AsBytes, FromZeroes, FromBytes)]
+#[repr(C, packed)]
+pub struct StreamWindowHeader {
+    pub type_id: u8,
+    pub stream_id: u16,
+    pub max_offset: [u8; 6],
+}
+
+/// Lets the peer send data on a stream up to `max_offset`, extending the
+/// [`STREAM_WINDOW`] it starts with as the data is consumed.
+#[derive(Clone)]
+pub struct StreamWindowFrame {
+    bytes: Bytes,
+}
+
+impl Size for StreamWindowFrame {
+    #[inline(always)]
+    fn size(&self) -> usize {
+        size_of::<StreamWindowHeader>()
+    }
+}
+
+impl StreamWindowFrame {
+    const TYPE_ID: u8 = 16;
+
+    pub fn new(stream_id: u16, max_offset: u64) -> Self {
+        let header = StreamWindowHeader {
+            type_id: Self::TYPE_ID,
+            stream_id,
+            max_offset: u64_to_six_u8(max_offset),
+        };
+        let bytes = BytesMut::from(AsBytes::as_bytes(&header)).into();
+        StreamWindowFrame { bytes }
+    }
+
+    pub fn header(&self) -> &StreamWindowHeader {
+        StreamWindowHeader::ref_from(self.bytes.as_ref())
+            .expect("Failed to reference StreamWindowHeader")
+    }
+
+    pub fn type_id(&self) -> u8 {
+        self.header().type_id
+    }
+
+    pub fn stream_id(&self) -> u16 {
+        self.header().stream_id
+    }
+
+    pub fn max_offset(&self) -> u64 {
+        six_u8_to_u64(&self.header().max_offset)
+    }
+}
+
+impl Parse for StreamWindowFrame {
+    fn parse(bytes: &mut Bytes) -> Result<Frame, anyhow::Error> {
+        // TODO bounds check
+        let bytes = bytes.split_to(size_of::<StreamWindowHeader>());
+        Ok(StreamWindowFrame { bytes }.into())
+    }
+}
+
+impl Assemble for StreamWindowFrame {
+    fn assemble(&self) -> BytesMut {
+        self.bytes.clone().into()
+    }
+}
+
+impl Debug for StreamWindowFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamWindow")
+            .field("stream_id", &self.stream_id())
+            .field("max_offset", &self.max_offset())
+            .finish()
+    }
+}
+
 #[derive(Clone)]
 pub struct Packet {
     header_bytes: Bytes,
@@ -1170,6 +1749,11 @@ impl Packet {
                 9 => ChecksumFrame::parse(&mut frame_bytes)?,
                 10 => StatFrame::parse(&mut frame_bytes)?,
                 11 => ListFrame::parse(&mut frame_bytes)?,
+                12 => MetadataFrame::parse(&mut frame_bytes)?,
+                13 => PathChallengeFrame::parse(&mut frame_bytes)?,
+                14 => PathResponseFrame::parse(&mut frame_bytes)?,
+                15 => StopFrame::parse(&mut frame_bytes)?,
+                16 => StreamWindowFrame::parse(&mut frame_bytes)?,
                 _ => return Err(anyhow!("Unknown frame type")),
             });
         }
@@ -1204,6 +1788,11 @@ impl Packet {
     pub fn add_frame(&mut self, frame: Frame) {
         self.frames.push(frame);
     }
+
+    /// Move the packet to another connection id, e.g. after the id changed.
+    pub fn set_connection_id(&mut self, connection_id: u32) {
+        self.header_bytes = Packet::new(connection_id, self.packet_id()).header_bytes;
+    }
 }
 
 impl Assemble for Packet {
@@ -1237,6 +1826,11 @@ pub enum Frame {
     Checksum(ChecksumFrame),
     Stat(StatFrame),
     List(ListFrame),
+    Metadata(MetadataFrame),
+    PathChallenge(PathChallengeFrame),
+    PathResponse(PathResponseFrame),
+    Stop(StopFrame),
+    StreamWindow(StreamWindowFrame),
 }
 
 impl Frame {
@@ -1254,6 +1848,11 @@ impl Frame {
             Frame::Checksum(frame) => frame.stream_id(),
             Frame::Stat(frame) => frame.stream_id(),
             Frame::List(frame) => frame.stream_id(),
+            Frame::Metadata(frame) => frame.stream_id(),
+            Frame::PathChallenge(_) => 0,
+            Frame::PathResponse(_) => 0,
+            Frame::Stop(frame) => frame.stream_id(),
+            Frame::StreamWindow(frame) => frame.stream_id(),
         }
     }
 
@@ -1305,6 +1904,26 @@ impl Frame {
         matches!(self, Frame::List(_))
     }
 
+    pub fn is_metadata(&self) -> bool {
+        matches!(self, Frame::Metadata(_))
+    }
+
+    pub fn is_path_challenge(&self) -> bool {
+        matches!(self, Frame::PathChallenge(_))
+    }
+
+    pub fn is_path_response(&self) -> bool {
+        matches!(self, Frame::PathResponse(_))
+    }
+
+    pub fn is_stop(&self) -> bool {
+        matches!(self, Frame::Stop(_))
+    }
+
+    pub fn is_stream_window(&self) -> bool {
+        matches!(self, Frame::StreamWindow(_))
+    }
+
     pub fn ack(&self) -> Option<&AckFrame> {
         match self {
             Frame::Ack(frame) => Some(frame),
@@ -1388,6 +2007,41 @@ impl Frame {
             _ => None,
         }
     }
+
+    pub fn metadata(&self) -> Option<&MetadataFrame> {
+        match self {
+            Frame::Metadata(frame) => Some(frame),
+            _ => None,
+        }
+    }
+
+    pub fn path_challenge(&self) -> Option<&PathChallengeFrame> {
+        match self {
+            Frame::PathChallenge(frame) => Some(frame),
+            _ => None,
+        }
+    }
+
+    pub fn path_response(&self) -> Option<&PathResponseFrame> {
+        match self {
+            Frame::PathResponse(frame) => Some(frame),
+            _ => None,
+        }
+    }
+
+    pub fn stop(&self) -> Option<&StopFrame> {
+        match self {
+            Frame::Stop(frame) => Some(frame),
+            _ => None,
+        }
+    }
+
+    pub fn stream_window(&self) -> Option<&StreamWindowFrame> {
+        match self {
+            Frame::StreamWindow(frame) => Some(frame),
+            _ => None,
+        }
+    }
 }
 
 impl Debug for Frame {
@@ -1405,6 +2059,11 @@ impl Debug for Frame {
             Frame::Checksum(frame) => frame.fmt(f),
             Frame::Stat(frame) => frame.fmt(f),
             Frame::List(frame) => frame.fmt(f),
+            Frame::Metadata(frame) => frame.fmt(f),
+            Frame::PathChallenge(frame) => frame.fmt(f),
+            Frame::PathResponse(frame) => frame.fmt(f),
+            Frame::Stop(frame) => frame.fmt(f),
+            Frame::StreamWindow(frame) => frame.fmt(f),
         }
     }
 }
@@ -1424,6 +2083,11 @@ impl Assemble for Frame {
             Frame::Checksum(frame) => frame.assemble(),
             Frame::Stat(frame) => frame.assemble(),
             Frame::List(frame) => frame.assemble(),
+            Frame::Metadata(frame) => frame.assemble(),
+            Frame::PathChallenge(frame) => frame.assemble(),
+            Frame::PathResponse(frame) => frame.assemble(),
+            Frame::Stop(frame) => frame.assemble(),
+            Frame::StreamWindow(frame) => frame.assemble(),
         }
     }
 }
@@ -1443,6 +2107,11 @@ impl Size for Frame {
             Frame::Checksum(frame) => frame.size(),
             Frame::Stat(frame) => frame.size(),
             Frame::List(frame) => frame.size(),
+            Frame::Metadata(frame) => frame.size(),
+            Frame::PathChallenge(frame) => frame.size(),
+            Frame::PathResponse(frame) => frame.size(),
+            Frame::Stop(frame) => frame.size(),
+            Frame::StreamWindow(frame) => frame.size(),
         }
     }
 }
@@ -1519,6 +2188,36 @@ impl From<ListFrame> for Frame {
     }
 }
 
+impl From<MetadataFrame> for Frame {
+    fn from(frame: MetadataFrame) -> Self {
+        Frame::Metadata(frame)
+    }
+}
+
+impl From<PathChallengeFrame> for Frame {
+    fn from(frame: PathChallengeFrame) -> Self {
+        Frame::PathChallenge(frame)
+    }
+}
+
+impl From<PathResponseFrame> for Frame {
+    fn from(frame: PathResponseFrame) -> Self {
+        Frame::PathResponse(frame)
+    }
+}
+
+impl From<StopFrame> for Frame {
+    fn from(frame: StopFrame) -> Self {
+        Frame::Stop(frame)
+    }
+}
+
+impl From<StreamWindowFrame> for Frame {
+    fn from(frame: StreamWindowFrame) -> Self {
+        Frame::StreamWindow(frame)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1606,6 +2305,68 @@ mod tests {
         assert_eq!(bytes1, bytes2);
     }
 
+    #[test]
+    fn test_error_code_roundtrip() {
+        let mut packet1 = Packet::new(1, 2);
+        packet1.add_frame(
+            ErrorFrame::with_code(7, ErrorCode::PermissionDenied, "Permission denied").into(),
+        );
+        let packet2 = Packet::parse(packet1.assemble().into()).expect("Parsing failed");
+        let frame = packet2.frames[0].error().expect("Expected ErrorFrame");
+        assert_eq!(frame.stream_id(), 7);
+        assert_eq!(frame.error_code(), ErrorCode::PermissionDenied);
+        assert_eq!(frame.message(), "Permission denied");
+    }
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        let after = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let before = UNIX_EPOCH - Duration::new(86_400, 250_000_000);
+        let mut packet1 = Packet::new(1, 2);
+        packet1.add_frame(MetadataFrame::new(3, 4096, 0o100644, after).into());
+        packet1.add_frame(MetadataFrame::new(4, 0, 0o100755, before).into());
+        let packet2 = Packet::parse(packet1.assemble().into()).expect("Parsing failed");
+        let frame = packet2.frames[0]
+            .metadata()
+            .expect("Expected MetadataFrame");
+        assert_eq!(frame.stream_id(), 3);
+        assert_eq!(frame.file_size(), 4096);
+        assert_eq!(frame.mode(), 0o100644);
+        assert_eq!(frame.mtime(), after);
+        let frame = packet2.frames[1]
+            .metadata()
+            .expect("Expected MetadataFrame");
+        assert_eq!(frame.mtime(), before);
+    }
+
+    #[test]
+    fn test_path_probe_roundtrip() {
+        let mut packet1 = Packet::new(1, 0);
+        packet1.add_frame(PathChallengeFrame::new(u64::MAX - 1).into());
+        packet1.add_frame(PathResponseFrame::new(42).into());
+        let packet2 = Packet::parse(packet1.assemble().into()).expect("Parsing failed");
+        let challenge = packet2.frames[0]
+            .path_challenge()
+            .expect("Expected PathChallengeFrame");
+        assert_eq!(challenge.nonce(), u64::MAX - 1);
+        let response = packet2.frames[1]
+            .path_response()
+            .expect("Expected PathResponseFrame");
+        assert_eq!(response.nonce(), 42);
+    }
+
+    #[test]
+    fn test_stream_window_roundtrip() {
+        let mut packet1 = Packet::new(1, 2);
+        packet1.add_frame(StreamWindowFrame::new(7, (1 << 40) + 5).into());
+        let packet2 = Packet::parse(packet1.assemble().into()).expect("Parsing failed");
+        let window = packet2.frames[0]
+            .stream_window()
+            .expect("Expected StreamWindowFrame");
+        assert_eq!(window.stream_id(), 7);
+        assert_eq!(window.max_offset(), (1 << 40) + 5);
+    }
+
     #[test]
     fn test_assemble_and_parse_simple_packet() {
         let packet1 = Packet::new(1, 2);
@@ -1615,6 +2376,38 @@ mod tests {
         assert_eq!(bytes1, bytes2);
     }
 
+    #[test]
+    fn test_list_entries() {
+        let entries: Vec<ListEntry> = (0..100)
+            .map(|i| ListEntry {
+                path: PathBuf::from(format!("data/2026-10-{:02}/part {}.parquet", i % 30, i)),
+                is_dir: i % 10 == 0,
+                size: i * 1000,
+                mtime: 1_790_000_000 - i as i64,
+            })
+            .collect();
+
+        let answers = ListEntry::to_answers(3, &entries).unwrap();
+        assert!(answers.len() > 2);
+        assert!(answers.last().unwrap().payload().is_empty());
+        let mut parsed = Vec::new();
+        for answer in &answers {
+            assert!(answer.payload().len() <= ListEntry::MAX_ANSWER_SIZE);
+            parsed.extend(ListEntry::parse_answer(answer.payload()).unwrap());
+        }
+        assert_eq!(parsed, entries);
+
+        //a listing without an entry would look complete
+        let broken = ListEntry {
+            path: PathBuf::from("a\nf 1 2 b"),
+            ..entries[0].clone()
+        };
+        assert!(ListEntry::to_answers(3, &[broken]).is_err());
+
+        assert!("x 1 2 a".parse::<ListEntry>().is_err());
+        assert!("f 1 a".parse::<ListEntry>().is_err());
+    }
+
     //#[test] // generates test data
     #[allow(dead_code)]
     fn write_ack_packet_to_file() {